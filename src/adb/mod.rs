@@ -0,0 +1,96 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, TcpStream},
+    process::{Command, Output},
+};
+
+use log::{debug, info};
+use nusb::DeviceInfo;
+
+use crate::{
+    config::Adb,
+    error::Error,
+    net::{Framed, TcpSource},
+};
+
+const USB_CLASS_VENDOR: u8 = 0xff;
+const ADB_SUBCLASS: u8 = 0x42;
+
+/// Whether the device exposes an ADB interface, which it only does
+/// with USB debugging enabled.
+pub(crate) fn has_adb_interface(device_info: &DeviceInfo) -> bool {
+    device_info.interfaces().any(|interface| {
+        interface.class() == USB_CLASS_VENDOR && interface.subclass() == ADB_SUBCLASS
+    })
+}
+
+/// A local port that `adb forward` tunnels to the touche app on a phone.
+/// The forward goes away along with this.
+///
+/// ADB already made the user authorize this computer on the phone,
+/// so unlike Wi-Fi there's no pairing on top.
+pub(crate) struct Forward {
+    adb: String,
+    serial: String,
+    port: u16,
+}
+
+impl Forward {
+    pub(crate) fn new(settings: &Adb, serial: &str) -> Result<Forward, Error> {
+        // tcp:0 lets adb pick a free port and print it
+        let output = run(Command::new(&settings.path)
+            .args(["-s", serial, "forward", "tcp:0"])
+            .arg(format!("tcp:{}", settings.port)))?;
+        let port = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| {
+                Error::Adb(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "adb forward didn't say which port it picked",
+                ))
+            })?;
+        info!(
+            "forwarding localhost:{} to port {} of {}",
+            port, settings.port, serial
+        );
+        Ok(Forward {
+            adb: settings.path.clone(),
+            serial: serial.to_owned(),
+            port,
+        })
+    }
+
+    /// Connects to the app through the forward. It connects even when the app
+    /// isn't listening, the connection just closes right after.
+    pub(crate) fn connect(&self) -> Result<TcpSource, Error> {
+        let stream =
+            TcpStream::connect((Ipv4Addr::LOCALHOST, self.port)).map_err(Error::Network)?;
+        Ok(TcpSource::new(Framed::new(stream).map_err(Error::Network)?))
+    }
+}
+
+impl Drop for Forward {
+    fn drop(&mut self) {
+        let removed = run(Command::new(&self.adb).args([
+            "-s",
+            &self.serial,
+            "forward",
+            "--remove",
+            &format!("tcp:{}", self.port),
+        ]));
+        // the phone is usually unplugged by now, taking the forward with it
+        if let Err(e) = removed {
+            debug!("failed to remove the adb forward: {}", e);
+        }
+    }
+}
+
+fn run(command: &mut Command) -> Result<Output, Error> {
+    let output = command.output().map_err(Error::Adb)?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+        return Err(Error::Adb(io::Error::other(message)));
+    }
+    Ok(output)
+}
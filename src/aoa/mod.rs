@@ -1,28 +1,37 @@
 pub(crate) mod utils;
 
+use std::{
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
 use futures_lite::future::block_on;
-use log::{error, info};
+use log::info;
 use nusb::{
     DeviceInfo, Interface,
-    transfer::{Direction, RequestBuffer, ResponseBuffer, TransferError},
+    transfer::{Direction, Queue, RequestBuffer, ResponseBuffer},
 };
 
+use crate::{error::Error, source::ToucheSource};
+
+const READ_BUFFER_LEN: usize = 16384;
+
 pub(crate) struct AoaDevice {
     interface: Interface,
-    in_endpoint_address: u8,
+    // reads go through a queue so a timed out read leaves its transfer
+    // pending instead of cancelling it and losing whatever was in flight
+    in_queue: Mutex<Queue<RequestBuffer>>,
     out_endpoint_address: u8,
 }
 
 impl AoaDevice {
-    pub(crate) fn new(aoa_device_info: DeviceInfo) -> Result<AoaDevice, ()> {
+    pub(crate) fn new(aoa_device_info: DeviceInfo) -> Result<AoaDevice, Error> {
         info!("attempting to open the AOA device...");
-        let device = aoa_device_info.open().map_err(|_| {
-            error!("failed to open the AOA device!");
-        })?;
+        let device = aoa_device_info.open().map_err(Error::UsbOpen)?;
         info!("attempting to claim the interface...");
-        let interface = device.claim_interface(0).map_err(|_| {
-            error!("failed to claim the interface!");
-        })?;
+        let interface = device.claim_interface(0).map_err(Error::UsbClaim)?;
 
         let binding = interface.clone();
         let descriptors: Vec<_> = binding.descriptors().collect();
@@ -38,7 +47,7 @@ impl AoaDevice {
         let in_endpoint = match in_endpoint {
             Some(endpoint) => endpoint,
             None => {
-                return Err(());
+                return Err(Error::NoEndpoint { direction: "IN" });
             }
         };
         let out_endpoint = endpoints
@@ -47,22 +56,79 @@ impl AoaDevice {
         let out_endpoint = match out_endpoint {
             Some(endpoint) => endpoint,
             None => {
-                return Err(());
+                return Err(Error::NoEndpoint { direction: "OUT" });
             }
         };
+        let in_queue = Mutex::new(interface.bulk_in_queue(in_endpoint.address()));
         Ok(AoaDevice {
             interface,
-            in_endpoint_address: in_endpoint.address(),
+            in_queue,
             out_endpoint_address: out_endpoint.address(),
         })
     }
 
-    pub(crate) fn read(&self) -> Result<Vec<u8>, TransferError> {
-        let buffer = RequestBuffer::new(16384);
-        block_on(self.interface.bulk_in(self.in_endpoint_address, buffer)).into_result()
+    pub(crate) fn read(&self) -> Result<Vec<u8>, Error> {
+        let mut queue = self.in_queue.lock().unwrap();
+        if queue.pending() == 0 {
+            queue.submit(RequestBuffer::new(READ_BUFFER_LEN));
+        }
+        let data = block_on(queue.next_complete())
+            .into_result()
+            .map_err(Error::Transfer)?;
+        Ok(data)
+    }
+
+    /// Like [`AoaDevice::read`], but gives up after `timeout` and returns `Ok(None)`.
+    /// The transfer stays queued, so the next read picks up where this one left off.
+    pub(crate) fn read_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+        let mut queue = self.in_queue.lock().unwrap();
+        if queue.pending() == 0 {
+            queue.submit(RequestBuffer::new(READ_BUFFER_LEN));
+        }
+
+        let deadline = Instant::now() + timeout;
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(completion) = queue.poll_next(&mut cx) {
+                let data = completion.into_result().map_err(Error::Transfer)?;
+                return Ok(Some(data));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
+
+    pub(crate) fn write(&self, data: Vec<u8>) -> Result<ResponseBuffer, Error> {
+        block_on(self.interface.bulk_out(self.out_endpoint_address, data))
+            .into_result()
+            .map_err(Error::Transfer)
+    }
+}
+
+impl ToucheSource for AoaDevice {
+    fn handshake(&mut self, request: &[u8]) -> Result<Vec<u8>, Error> {
+        self.write(request.to_vec())?;
+        self.read()
+    }
+
+    fn read_frame(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+        self.read_timeout(timeout)
+    }
+
+    fn send_opcode(&mut self, opcode: &[u8]) -> Result<(), Error> {
+        self.write(opcode.to_vec())?;
+        Ok(())
     }
+}
+
+struct ThreadWaker(Thread);
 
-    pub(crate) fn write(&self, data: Vec<u8>) -> Result<ResponseBuffer, TransferError> {
-        block_on(self.interface.bulk_out(self.out_endpoint_address, data)).into_result()
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
     }
 }
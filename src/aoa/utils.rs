@@ -1,6 +1,6 @@
 use futures_lite::future::block_on;
 use nusb::{
-    Device, DeviceInfo, list_devices,
+    Device, DeviceInfo,
     transfer::{ControlIn, ControlOut, ControlType, Recipient, ResponseBuffer, TransferError},
 };
 
@@ -44,13 +44,14 @@ pub(crate) fn introduce_host(
     version: &str,
     uri: &str,
     serial_number: &str,
-) {
-    let _ = send_str(handle, manufacturer_name, MANUFACTURER_NAME_ID);
-    let _ = send_str(handle, model_name, MODEL_NAME_ID);
-    let _ = send_str(handle, description, DESCRIPTION_ID);
-    let _ = send_str(handle, version, VERSION_ID);
-    let _ = send_str(handle, uri, URI_ID);
-    let _ = send_str(handle, serial_number, SERIAL_NUMBER_ID);
+) -> Result<(), TransferError> {
+    send_str(handle, manufacturer_name, MANUFACTURER_NAME_ID)?;
+    send_str(handle, model_name, MODEL_NAME_ID)?;
+    send_str(handle, description, DESCRIPTION_ID)?;
+    send_str(handle, version, VERSION_ID)?;
+    send_str(handle, uri, URI_ID)?;
+    send_str(handle, serial_number, SERIAL_NUMBER_ID)?;
+    Ok(())
 }
 
 pub(crate) fn make_aoa(handle: &Device) -> Result<ResponseBuffer, TransferError> {
@@ -0,0 +1,39 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use crate::config::state_dir;
+
+/// Where a daemonized driver writes its logs.
+pub(crate) fn log_path() -> PathBuf {
+    state_dir().join("touche-driver.log")
+}
+
+/// Starts this very binary again, detached from the terminal,
+/// with the same arguments minus `--daemon`. Returns the child's pid.
+pub(crate) fn daemonize() -> io::Result<u32> {
+    let log_path = log_path();
+    if let Some(dir) = log_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)?;
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1).filter(|arg| arg != "--daemon"))
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    // its own process group, so ^C in the terminal doesn't reach it
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let child = command.spawn()?;
+    Ok(child.id())
+}
@@ -0,0 +1,226 @@
+pub(crate) mod daemon;
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use log::LevelFilter;
+use nusb::DeviceInfo;
+
+use crate::{
+    aoa::utils::is_aoa,
+    config::{Profile, state_dir},
+    data::rotation::Rotation,
+    net::DEFAULT_PORT,
+    permissions::{DEFAULT_RULES_PATH, looks_like_phone},
+};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "making your phone a touchepad and graphics tablet",
+    args_conflicts_with_subcommands = true
+)]
+pub(crate) struct Cli {
+    /// off, error, warn, info, debug or trace. Overrides RUST_LOG
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub(crate) log_level: Option<LevelFilter>,
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+    /// `run` options, for when no subcommand is given
+    #[command(flatten)]
+    pub(crate) run: RunArgs,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Drive connected phones. This is the default
+    Run(RunArgs),
+    /// Drive connected phones and save everything they send, for `replay`
+    Record {
+        /// directory the recordings go to, one file per session
+        #[arg(long, value_name = "DIR", default_value_os_t = recordings_dir())]
+        output: PathBuf,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Play a recording back through new virtual devices, no phone needed
+    Replay {
+        /// a recording made by `record`
+        file: PathBuf,
+        /// 2 plays twice as fast, 0 as fast as possible
+        #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+        speed: f32,
+        #[command(flatten)]
+        profile: ProfileArgs,
+    },
+    /// Let a new phone pair over the network, for the next few minutes
+    Pair,
+    /// Pretend to be a phone on the network, to try `--listen` without one
+    TestClient(TestClientArgs),
+    /// List USB devices that look like phones
+    ListDevices {
+        /// list every USB device, not only phones
+        #[arg(long)]
+        all: bool,
+    },
+    /// Check uinput, groups and USB permissions, and explain what's wrong
+    Doctor,
+    /// Write udev rules for the phones plugged in right now
+    Udev {
+        /// where to write the rules, `-` prints them instead
+        #[arg(default_value = DEFAULT_RULES_PATH)]
+        path: String,
+    },
+}
+
+#[derive(Args, Clone, Default)]
+pub(crate) struct RunArgs {
+    #[command(flatten)]
+    pub(crate) profile: ProfileArgs,
+    /// only drive the phone with this USB serial number. Can be repeated
+    #[arg(long = "serial", value_name = "SERIAL")]
+    pub(crate) serials: Vec<String>,
+    /// only switch phones from this USB vendor, in hex (like 04e8). Can be repeated
+    #[arg(long = "vendor", value_name = "VENDOR_ID", value_parser = parse_vendor_id)]
+    pub(crate) vendors: Vec<u16>,
+    /// also take phones over Wi-Fi on this address, like 0.0.0.0:7420
+    #[arg(long, value_name = "ADDR")]
+    pub(crate) listen: Option<SocketAddr>,
+    /// detach from the terminal and log to a file
+    #[arg(long, conflicts_with = "foreground")]
+    pub(crate) daemon: bool,
+    /// stay attached to the terminal. This is the default
+    #[arg(long)]
+    pub(crate) foreground: bool,
+}
+
+/// Which config to use and the flags that override it.
+#[derive(Args, Clone, Default)]
+pub(crate) struct ProfileArgs {
+    /// config file to use instead of the one in ~/.config/touche-driver
+    #[arg(long, value_name = "PATH")]
+    pub(crate) config: Option<PathBuf>,
+    /// don't create the graphics tablet
+    #[arg(long, conflicts_with = "no_touchpad")]
+    pub(crate) no_tablet: bool,
+    /// don't create the touchpad
+    #[arg(long)]
+    pub(crate) no_touchpad: bool,
+    /// rotate input by 0, 90, 180 or 270 degrees, whatever the phone reports
+    #[arg(long, value_name = "DEGREES", value_parser = parse_rotation)]
+    pub(crate) rotation: Option<Rotation>,
+}
+
+#[derive(Args)]
+pub(crate) struct TestClientArgs {
+    /// where the driver listens
+    #[arg(default_value_t = SocketAddr::from(([127, 0, 0, 1], DEFAULT_PORT)))]
+    pub(crate) addr: SocketAddr,
+    /// the pairing code `touche-driver pair` printed. Leave it out to come back after pairing
+    #[arg(long, requires = "fingerprint")]
+    pub(crate) code: Option<String>,
+    /// the driver fingerprint `touche-driver pair` printed, to pair only with that driver
+    #[arg(long, requires = "code")]
+    pub(crate) fingerprint: Option<String>,
+    /// directory with the test client's key and the drivers it paired with
+    #[arg(long, value_name = "DIR", default_value_os_t = test_client_dir())]
+    pub(crate) keys: PathBuf,
+    /// the driver's pre-shared key, if it has one
+    #[arg(long)]
+    pub(crate) psk: Option<String>,
+    /// name to pair as, it ends up in the device names
+    #[arg(long, default_value = "test-client")]
+    pub(crate) label: String,
+    /// how long to draw for
+    #[arg(long, default_value_t = 10)]
+    pub(crate) seconds: u64,
+}
+
+impl ProfileArgs {
+    /// Flags that override the config, for every phone.
+    pub(crate) fn overrides(&self) -> Profile {
+        let mut overrides = Profile {
+            rotation: self.rotation,
+            ..Profile::default()
+        };
+        if self.no_tablet {
+            overrides.tablet.enabled = Some(false);
+        }
+        if self.no_touchpad {
+            overrides.touchpad.enabled = Some(false);
+        }
+        overrides
+    }
+}
+
+impl RunArgs {
+    /// Whether `--serial` and `--vendor` let this device through.
+    ///
+    /// Phones in accessory mode all report Google's vendor id,
+    /// so `--vendor` only decides which phones get switched.
+    pub(crate) fn selects(&self, device_info: &DeviceInfo) -> bool {
+        let serial_ok = self.serials.is_empty()
+            || device_info
+                .serial_number()
+                .is_some_and(|serial| self.serials.iter().any(|it| it == serial));
+        let vendor_ok = self.vendors.is_empty()
+            || is_aoa(device_info)
+            || self.vendors.contains(&device_info.vendor_id());
+        serial_ok && vendor_ok
+    }
+}
+
+fn parse_vendor_id(value: &str) -> Result<u16, String> {
+    let digits = value.trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("`{}` is not a hex vendor id", value))
+}
+
+fn parse_rotation(value: &str) -> Result<Rotation, String> {
+    value
+        .parse::<i32>()
+        .ok()
+        .and_then(Rotation::from_degrees)
+        .ok_or(format!("`{}` is not one of 0, 90, 180 or 270", value))
+}
+
+fn parse_speed(value: &str) -> Result<f32, String> {
+    value
+        .parse::<f32>()
+        .ok()
+        .filter(|speed| speed.is_finite() && *speed >= 0.0)
+        .ok_or(format!("`{}` is not a speed, try 1, 0.5 or 10", value))
+}
+
+fn recordings_dir() -> PathBuf {
+    state_dir().join("recordings")
+}
+
+fn test_client_dir() -> PathBuf {
+    state_dir().join("test-client")
+}
+
+/// `touche-driver list-devices`
+pub(crate) fn list_devices(all: bool) -> std::io::Result<()> {
+    let mut found = false;
+    for device_info in nusb::list_devices()? {
+        let accessory = is_aoa(&device_info);
+        if !all && !looks_like_phone(&device_info) {
+            continue;
+        }
+        found = true;
+        println!(
+            "{:03}-{:03}  {:04x}:{:04x}  {:<20}  {}{}",
+            device_info.bus_number(),
+            device_info.device_address(),
+            device_info.vendor_id(),
+            device_info.product_id(),
+            device_info.serial_number().unwrap_or("-"),
+            device_info.product_string().unwrap_or("unknown device"),
+            if accessory { " (accessory mode)" } else { "" }
+        );
+    }
+    if !found {
+        println!("no phones found. Unlock yours and enable USB debugging");
+    }
+    Ok(())
+}
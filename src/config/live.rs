@@ -0,0 +1,161 @@
+use std::{
+    path::{self, PathBuf},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver},
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use notify::{
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{AccessKind, AccessMode, ModifyKind},
+};
+
+use crate::driver::DriverOptions;
+
+use super::Config;
+
+/// Editors tend to save in a few steps, this gives them time to finish.
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// The config currently in effect, swapped whole whenever the file changes.
+pub(crate) struct LiveConfig {
+    config: RwLock<Arc<Config>>,
+    /// bumped on every reload, so sessions can tell cheaply that they're behind
+    generation: AtomicU64,
+}
+
+impl LiveConfig {
+    pub(crate) fn new(config: Config) -> LiveConfig {
+        LiveConfig {
+            config: RwLock::new(Arc::new(config)),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn get(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    fn replace(&self, config: Config) {
+        *self.config.write().unwrap() = Arc::new(config);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+}
+
+/// Driver options of a single phone that follow config reloads.
+pub(crate) struct LiveOptions {
+    config: Arc<LiveConfig>,
+    label: String,
+    generation: u64,
+    options: Arc<DriverOptions>,
+}
+
+impl LiveOptions {
+    pub(crate) fn new(config: Arc<LiveConfig>, label: &str) -> LiveOptions {
+        let generation = config.generation();
+        let options = Arc::new(config.get().driver_options(label));
+        LiveOptions {
+            config,
+            label: label.to_owned(),
+            generation,
+            options,
+        }
+    }
+
+    pub(crate) fn get(&self) -> Arc<DriverOptions> {
+        Arc::clone(&self.options)
+    }
+
+    /// Picks up a reloaded config. Returns `true` only when the options of
+    /// this phone actually changed, edits to other profiles don't count.
+    pub(crate) fn refresh(&mut self) -> bool {
+        let generation = self.config.generation();
+        if generation == self.generation {
+            return false;
+        }
+        self.generation = generation;
+        let options = self.config.get().driver_options(&self.label);
+        if options == *self.options {
+            return false;
+        }
+        self.options = Arc::new(options);
+        true
+    }
+}
+
+/// Reloads the config whenever `path` changes, until the returned watcher is dropped.
+///
+/// The whole directory is watched, since most editors save by writing a new
+/// file and renaming it over the old one. A config that fails to load is
+/// logged and ignored, the previous one stays in effect.
+pub(crate) fn watch(path: PathBuf, live: Arc<LiveConfig>) -> notify::Result<RecommendedWatcher> {
+    // events come with absolute paths, and `touche.toml` has no parent to watch.
+    // A file that doesn't exist yet can't be resolved, its directory can
+    let Some(path) = path.canonicalize().ok().or_else(|| {
+        let path = path::absolute(&path).ok()?;
+        Some(path.parent()?.canonicalize().ok()?.join(path.file_name()?))
+    }) else {
+        return Err(notify::Error::path_not_found().add_path(path));
+    };
+    let Some(dir) = path.parent().filter(|dir| dir.is_dir()) else {
+        return Err(notify::Error::path_not_found().add_path(path));
+    };
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    info!("watching {} for changes", path.display());
+
+    thread::Builder::new()
+        .name("touche-config".to_owned())
+        .spawn(move || reload_loop(&path, &live, &rx))?;
+    Ok(watcher)
+}
+
+fn reload_loop(path: &PathBuf, live: &LiveConfig, rx: &Receiver<notify::Result<Event>>) {
+    while let Ok(event) = rx.recv() {
+        match event {
+            Ok(event) if event.paths.contains(path) && changes_content(&event.kind) => {}
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("config watcher error: {}", e);
+                continue;
+            }
+        }
+        thread::sleep(SETTLE);
+        while rx.try_recv().is_ok() {}
+
+        match Config::load(Some(path)) {
+            Ok(mut config) => {
+                config.overrides = live.get().overrides.clone();
+                live.replace(config);
+                info!("config reloaded");
+            }
+            Err(e) => {
+                error!("{}", e);
+                info!("keeping the previous config");
+            }
+        }
+    }
+    debug!("config watcher stopped");
+}
+
+/// Whether the event can mean new contents. Our own reads show up as
+/// events too, and reloading on those would never end.
+fn changes_content(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Create(_) | EventKind::Remove(_) => true,
+        EventKind::Modify(ModifyKind::Metadata(_)) => false,
+        EventKind::Modify(_) => true,
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Access(_) | EventKind::Any | EventKind::Other => false,
+    }
+}
@@ -0,0 +1,558 @@
+pub(crate) mod live;
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{debug, info};
+use serde::Deserialize;
+
+use crate::{
+    data::rotation::Rotation,
+    driver::DriverOptions,
+    error::Error,
+    graphics_tablet::{area::Rect, pressure::PressureCurve},
+    net::DEFAULT_PORT,
+    sink::SinkKind,
+};
+
+/// The config file, with everything that used to be hardcoded.
+///
+/// ```toml
+/// [accessory]
+/// manufacturer = "bpavuk"
+///
+/// [defaults]
+/// proximity_timeout_ms = 2000
+/// tablet.pressure.curve = { gamma = 1.4 }
+///
+/// [profiles.R58M123ABC]
+/// rotation = 90
+/// touchpad.enabled = false
+/// ```
+///
+/// Settings of a phone are looked up in its profile first, then in
+/// `[defaults]`, and whatever is left unset keeps its built-in value.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) accessory: Accessory,
+    pub(crate) network: Network,
+    pub(crate) adb: Adb,
+    pub(crate) defaults: Profile,
+    /// keyed by session label: the USB serial number, or `net-<label>`
+    /// for a phone on the network
+    pub(crate) profiles: HashMap<String, Profile>,
+    /// command-line flags, they beat everything in the file
+    #[serde(skip)]
+    pub(crate) overrides: Profile,
+}
+
+/// How the driver introduces itself to a phone when switching it to
+/// accessory mode, and how long it gives the phone to settle.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Accessory {
+    pub(crate) manufacturer: String,
+    pub(crate) model: String,
+    pub(crate) description: String,
+    pub(crate) version: String,
+    pub(crate) uri: String,
+    pub(crate) serial: String,
+    /// pause after a phone shows up before talking to it
+    pub(crate) hotplug_delay_ms: u64,
+    /// pause after opening a phone before asking for its AOA version
+    pub(crate) open_delay_ms: u64,
+}
+
+impl Default for Accessory {
+    fn default() -> Self {
+        Accessory {
+            manufacturer: "bpavuk".to_owned(),
+            model: "touche".to_owned(),
+            description: "making your phone a touchepad and graphics tablet".to_owned(),
+            version: "v0".to_owned(),    // TODO: change to v1 once it's done
+            uri: "what://".to_owned(),   // TODO
+            serial: "528491".to_owned(), // have you ever watched Inception?
+            hotplug_delay_ms: 100,
+            open_delay_ms: 1000,
+        }
+    }
+}
+
+/// Taking phones over Wi-Fi. Changes here need a restart.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Network {
+    /// where to listen for phones, like "0.0.0.0:7420". Off when unset
+    pub(crate) listen: Option<SocketAddr>,
+    /// pre-shared key every phone has to know, on top of being paired
+    pub(crate) psk: Option<String>,
+}
+
+/// Reaching phones through `adb forward` when they can't do accessory mode.
+/// Only phones with USB debugging enabled qualify.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Adb {
+    pub(crate) enabled: bool,
+    /// the adb binary, looked up in `$PATH` unless it's a path
+    pub(crate) path: String,
+    /// port the touche app listens on, on the phone
+    pub(crate) port: u16,
+}
+
+impl Default for Adb {
+    fn default() -> Self {
+        Adb {
+            enabled: true,
+            path: "adb".to_owned(),
+            port: DEFAULT_PORT,
+        }
+    }
+}
+
+/// Settings of a single phone, or the defaults for all of them.
+/// Everything is optional, so a profile only has to mention what it changes.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Profile {
+    /// 0, 90, 180 or 270, overriding what the phone reports
+    pub(crate) rotation: Option<Rotation>,
+    pub(crate) rebuild_on_resize: Option<bool>,
+    pub(crate) proximity_timeout_ms: Option<u64>,
+    pub(crate) stream_delay_ms: Option<u64>,
+    /// where input goes, like `["tablet", "touchpad"]`
+    pub(crate) sinks: Option<Vec<SinkKind>>,
+    pub(crate) tablet: TabletProfile,
+    pub(crate) touchpad: TouchpadProfile,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TabletProfile {
+    pub(crate) enabled: Option<bool>,
+    pub(crate) name: Option<String>,
+    pub(crate) vendor_id: Option<u16>,
+    pub(crate) product_id: Option<u16>,
+    pub(crate) distance_resolution: Option<i32>,
+    pub(crate) pressure: PressureProfile,
+    pub(crate) area: AreaProfile,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PressureProfile {
+    pub(crate) curve: Option<PressureCurve>,
+    pub(crate) min: Option<f32>,
+    pub(crate) max: Option<f32>,
+    pub(crate) activation: Option<f32>,
+    pub(crate) resolution: Option<i32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AreaProfile {
+    pub(crate) input: Option<Rect>,
+    pub(crate) desktop: Option<(i32, i32)>,
+    pub(crate) output: Option<Rect>,
+    pub(crate) preserve_aspect: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TouchpadProfile {
+    pub(crate) enabled: Option<bool>,
+    pub(crate) name: Option<String>,
+    pub(crate) vendor_id: Option<u16>,
+    pub(crate) product_id: Option<u16>,
+}
+
+impl Config {
+    /// Reads the config at `path`. No config at all is fine,
+    /// everything just keeps its built-in value.
+    pub(crate) fn load(path: Option<&Path>) -> Result<Config, Error> {
+        let Some(path) = path else {
+            info!("no config file found, using defaults");
+            return Ok(Config::default());
+        };
+        info!("loading config from {}", path.display());
+        let text = fs::read_to_string(path).map_err(|source| Error::ConfigRead {
+            path: path.to_owned(),
+            source,
+        })?;
+        let config: Config = toml::from_str(&text).map_err(|source| Error::ConfigParse {
+            path: path.to_owned(),
+            source,
+        })?;
+        config.check().map_err(|(key, reason)| Error::Config {
+            path: path.to_owned(),
+            key,
+            reason,
+        })?;
+        Ok(config)
+    }
+
+    /// The first setting that would make broken devices, as its full key
+    /// and what's wrong with it.
+    fn check(&self) -> Result<(), (String, &'static str)> {
+        self.defaults
+            .check()
+            .map_err(|(key, reason)| (format!("defaults.{}", key), reason))?;
+        for (label, profile) in &self.profiles {
+            profile
+                .check()
+                .map_err(|(key, reason)| (format!("profiles.{}.{}", label, key), reason))?;
+        }
+        Ok(())
+    }
+
+    /// Options for the phone with this session label,
+    /// which is its serial number whenever it has one.
+    pub(crate) fn driver_options(&self, label: &str) -> DriverOptions {
+        let mut options = DriverOptions::default();
+        self.defaults.apply(&mut options);
+        if let Some(profile) = self.profiles.get(label) {
+            debug!("using the config profile of {}", label);
+            profile.apply(&mut options);
+        }
+        self.overrides.apply(&mut options);
+        options
+    }
+}
+
+impl Profile {
+    fn check(&self) -> Result<(), (&'static str, &'static str)> {
+        let tablet = &self.tablet;
+        let pressure = &tablet.pressure;
+        let area = &tablet.area;
+        let unit = |value: Option<f32>| value.is_none_or(|value| (0.0..=1.0).contains(&value));
+        let checks = [
+            (
+                "proximity_timeout_ms",
+                // with 0 the driver spins on reads and the pen leaves
+                // proximity after every single frame
+                self.proximity_timeout_ms.is_none_or(|it| it > 0),
+                "has to be above 0",
+            ),
+            (
+                "tablet.distance_resolution",
+                tablet.distance_resolution.is_none_or(|it| it > 0),
+                "has to be above 0",
+            ),
+            (
+                "tablet.pressure.resolution",
+                pressure.resolution.is_none_or(|it| it > 0),
+                "has to be above 0",
+            ),
+            (
+                "tablet.pressure.min",
+                unit(pressure.min),
+                "has to be between 0 and 1",
+            ),
+            (
+                "tablet.pressure.max",
+                unit(pressure.max),
+                "has to be between 0 and 1",
+            ),
+            (
+                "tablet.pressure.max",
+                pressure
+                    .min
+                    .zip(pressure.max)
+                    .is_none_or(|(min, max)| min <= max),
+                "can't be below `tablet.pressure.min`",
+            ),
+            (
+                "tablet.pressure.activation",
+                unit(pressure.activation),
+                "has to be between 0 and 1",
+            ),
+            (
+                "tablet.area.input",
+                area.input.is_none_or(|rect| rect.has_area()),
+                "needs a width and height above 0",
+            ),
+            (
+                "tablet.area.output",
+                area.output.is_none_or(|rect| rect.has_area()),
+                "needs a width and height above 0",
+            ),
+            (
+                "tablet.area.desktop",
+                area.desktop
+                    .is_none_or(|(width, height)| width > 0 && height > 0),
+                "needs a width and height above 0",
+            ),
+        ];
+        for (key, ok, reason) in checks {
+            if !ok {
+                return Err((key, reason));
+            }
+        }
+        match &pressure.curve {
+            Some(curve) => curve
+                .check()
+                .map_err(|reason| ("tablet.pressure.curve", reason)),
+            None => Ok(()),
+        }
+    }
+
+    fn apply(&self, options: &mut DriverOptions) {
+        if let Some(rotation) = self.rotation {
+            options.rotation = Some(rotation);
+        }
+        set(&mut options.rebuild_on_resize, self.rebuild_on_resize);
+        if let Some(ms) = self.proximity_timeout_ms {
+            options.proximity_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.stream_delay_ms {
+            options.stream_delay = Duration::from_millis(ms);
+        }
+        set(&mut options.sinks, self.sinks.clone());
+
+        let tablet = &mut options.tablet;
+        toggle(&mut options.sinks, SinkKind::Tablet, self.tablet.enabled);
+        set(&mut tablet.name, self.tablet.name.clone());
+        set(&mut tablet.vendor_id, self.tablet.vendor_id);
+        set(&mut tablet.product_id, self.tablet.product_id);
+        set(
+            &mut tablet.distance_resolution,
+            self.tablet.distance_resolution,
+        );
+
+        let pressure = &self.tablet.pressure;
+        set(&mut tablet.pressure.curve, pressure.curve.clone());
+        set(&mut tablet.pressure.min, pressure.min);
+        set(&mut tablet.pressure.max, pressure.max);
+        set(&mut tablet.pressure.activation, pressure.activation);
+        set(&mut tablet.pressure.resolution, pressure.resolution);
+
+        let area = &self.tablet.area;
+        let mapping = &mut tablet.area;
+        if area.input.is_some() {
+            mapping.input = area.input;
+        }
+        if area.desktop.is_some() {
+            mapping.desktop = area.desktop;
+        }
+        if area.output.is_some() {
+            mapping.output = area.output;
+        }
+        set(&mut mapping.preserve_aspect, area.preserve_aspect);
+
+        let touchpad = &mut options.touchpad;
+        toggle(
+            &mut options.sinks,
+            SinkKind::Touchpad,
+            self.touchpad.enabled,
+        );
+        set(&mut touchpad.name, self.touchpad.name.clone());
+        set(&mut touchpad.vendor_id, self.touchpad.vendor_id);
+        set(&mut touchpad.product_id, self.touchpad.product_id);
+    }
+}
+
+fn set<T>(target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *target = value;
+    }
+}
+
+/// `enabled = true` adds the sink if it's missing, `false` takes it out.
+fn toggle(sinks: &mut Vec<SinkKind>, kind: SinkKind, enabled: Option<bool>) {
+    match enabled {
+        Some(true) if !sinks.contains(&kind) => sinks.push(kind),
+        Some(false) => sinks.retain(|it| *it != kind),
+        _ => {}
+    }
+}
+
+/// The config to use: `path` if given, otherwise the first one
+/// that exists in the XDG config dirs.
+pub(crate) fn find(path: Option<&Path>) -> Option<PathBuf> {
+    match path {
+        Some(path) => Some(path.to_owned()),
+        None => default_paths().into_iter().find(|path| path.is_file()),
+    }
+}
+
+/// `$XDG_CONFIG_HOME/touche-driver/config.toml`, then the same
+/// under every `$XDG_CONFIG_DIRS` entry, in that order.
+pub(crate) fn default_paths() -> Vec<PathBuf> {
+    let config_dirs = env::var("XDG_CONFIG_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/etc/xdg".to_owned());
+
+    config_home()
+        .into_iter()
+        .chain(config_dirs.split(':').map(PathBuf::from))
+        .filter(|dir| dir.is_absolute())
+        .map(|dir| dir.join("touche-driver").join("config.toml"))
+        .collect()
+}
+
+/// `$XDG_CONFIG_HOME/touche-driver`, where the driver keeps what it writes
+/// itself, like the phones it trusts.
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    config_home().map(|dir| dir.join("touche-driver"))
+}
+
+fn config_home() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+}
+
+/// `$XDG_STATE_HOME/touche-driver`, for logs and recordings.
+pub(crate) fn state_dir() -> PathBuf {
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .unwrap_or_else(env::temp_dir)
+        .join("touche-driver")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    fn check(text: &str) -> Result<(), (String, &'static str)> {
+        parse(text).check()
+    }
+
+    #[test]
+    fn nothing_set_keeps_the_built_in_options() {
+        assert_eq!(
+            Config::default().driver_options("R58M123ABC"),
+            DriverOptions::default()
+        );
+    }
+
+    #[test]
+    fn profile_beats_defaults_and_overrides_beat_both() {
+        let mut config = parse(
+            r#"
+            [defaults]
+            proximity_timeout_ms = 500
+            stream_delay_ms = 10
+            tablet.pressure.min = 0.1
+
+            [profiles.R58M123ABC]
+            proximity_timeout_ms = 800
+            rotation = 90
+            "#,
+        );
+        config.overrides.stream_delay_ms = Some(50);
+
+        let options = config.driver_options("R58M123ABC");
+
+        assert_eq!(options.proximity_timeout, Duration::from_millis(800));
+        assert_eq!(options.rotation, Some(Rotation::R90));
+        assert_eq!(options.stream_delay, Duration::from_millis(50));
+        // left alone by the profile, so the default still counts
+        assert_eq!(options.tablet.pressure.min, 0.1);
+    }
+
+    #[test]
+    fn other_phones_only_get_the_defaults() {
+        let config = parse(
+            r#"
+            [defaults]
+            proximity_timeout_ms = 500
+
+            [profiles.R58M123ABC]
+            proximity_timeout_ms = 800
+            "#,
+        );
+
+        let options = config.driver_options("net-tablet");
+
+        assert_eq!(options.proximity_timeout, Duration::from_millis(500));
+        assert_eq!(options.rotation, None);
+    }
+
+    #[test]
+    fn enabled_toggles_sinks() {
+        let config = parse(
+            r#"
+            [defaults]
+            touchpad.enabled = false
+
+            [profiles.R58M123ABC]
+            sinks = ["touchpad"]
+            tablet.enabled = true
+            "#,
+        );
+
+        assert_eq!(
+            config.driver_options("net-tablet").sinks,
+            [SinkKind::Tablet]
+        );
+        // the profile's list replaces what the defaults left, then its own toggles apply
+        assert_eq!(
+            config.driver_options("R58M123ABC").sinks,
+            [SinkKind::Touchpad, SinkKind::Tablet]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        assert!(toml::from_str::<Config>("[defaults]\nproximity_timeout = 2000").is_err());
+    }
+
+    #[test]
+    fn check_names_the_broken_key() {
+        assert_eq!(check(""), Ok(()));
+        assert_eq!(
+            check("[defaults]\nproximity_timeout_ms = 0"),
+            Err((
+                "defaults.proximity_timeout_ms".to_owned(),
+                "has to be above 0"
+            ))
+        );
+        assert_eq!(
+            check("[profiles.R58M123ABC]\ntablet.pressure.max = 1.5"),
+            Err((
+                "profiles.R58M123ABC.tablet.pressure.max".to_owned(),
+                "has to be between 0 and 1"
+            ))
+        );
+    }
+
+    #[test]
+    fn check_wants_min_pressure_below_max() {
+        let err = check("[defaults]\ntablet.pressure = { min = 0.6, max = 0.4 }").unwrap_err();
+
+        assert_eq!(err.0, "defaults.tablet.pressure.max");
+    }
+
+    #[test]
+    fn check_refuses_empty_areas() {
+        let err = check("[defaults]\ntablet.area.desktop = [1920, 0]").unwrap_err();
+
+        assert_eq!(err.0, "defaults.tablet.area.desktop");
+    }
+
+    #[test]
+    fn check_includes_the_pressure_curve() {
+        assert_eq!(
+            check("[defaults]\ntablet.pressure.curve = { gamma = 0.0 }"),
+            Err((
+                "defaults.tablet.pressure.curve".to_owned(),
+                "needs a gamma above 0"
+            ))
+        );
+    }
+}
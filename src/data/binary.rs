@@ -0,0 +1,408 @@
+//! Binary flavour of the touche protocol.
+//!
+//! Every record is framed as
+//!
+//! ```text
+//! version: u8 | record type: u8 | payload length: u16 LE | payload
+//! ```
+//!
+//! and payloads are made of fixed-width little-endian fields:
+//!
+//! ```text
+//! 0x01 screen size  width: i32, height: i32
+//! 0x02 stylus       x: f32, y: f32, flags: u8, pressure: f32,
+//!                   [tilt_x: f32, tilt_y: f32, orientation: f32, distance: f32,
+//!                   [state: u8]]
+//! 0x03 touch        x: f32, y: f32, touch_id: i32, flags: u8
+//! 0x04 rotation     degrees: u16, a multiple of 90
+//! ```
+//!
+//! Bit 0 of `flags` is "pressed". The rest of the stylus flags say which
+//! optional fields are meaningful: bit 1 for `pressure`, bit 2 for both tilts,
+//! bit 3 for `orientation` and bit 4 for `distance`. Bits 5 and 6 are the
+//! primary and secondary barrel buttons, bit 7 means the eraser end is in
+//! use. Bit 0 of `state` means the pen has left proximity. The bracketed
+//! fields may be left out entirely. Payloads may be longer than listed here - newer
+//! phones can append fields and older drivers will skip them.
+
+use super::{ParseError, ParseMode, ParsedFrame, StylusTool, ToucheData, rotation::Rotation};
+
+pub(crate) const BINARY_VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 4;
+
+const RECORD_SCREEN_SIZE: u8 = 0x01;
+const RECORD_STYLUS: u8 = 0x02;
+const RECORD_TOUCH: u8 = 0x03;
+const RECORD_ROTATION: u8 = 0x04;
+
+const FLAG_PRESSED: u8 = 1 << 0;
+const FLAG_HAS_PRESSURE: u8 = 1 << 1;
+const FLAG_HAS_TILT: u8 = 1 << 2;
+const FLAG_HAS_ORIENTATION: u8 = 1 << 3;
+const FLAG_HAS_DISTANCE: u8 = 1 << 4;
+const FLAG_PRIMARY_BUTTON: u8 = 1 << 5;
+const FLAG_SECONDARY_BUTTON: u8 = 1 << 6;
+const FLAG_ERASER: u8 = 1 << 7;
+const STATE_OUT_OF_PROXIMITY: u8 = 1 << 0;
+
+const STYLUS_LEN: usize = 13;
+const STYLUS_EXTENDED_LEN: usize = 29;
+const STYLUS_STATE_LEN: usize = 30;
+const TOUCH_LEN: usize = 13;
+const ROTATION_LEN: usize = 2;
+
+/// Decodes every complete record at the start of `input` into `frame`.
+///
+/// Returns how many bytes were consumed. Whatever is left is the beginning
+/// of a record that hasn't fully arrived yet.
+pub(crate) fn decode_records(
+    input: &[u8],
+    mode: ParseMode,
+    frame: &mut ParsedFrame,
+) -> Result<usize, ParseError> {
+    let mut offset = 0;
+    let mut record = 0;
+    while let Some(header) = input.get(offset..offset + HEADER_LEN) {
+        let version = header[0];
+        if version != BINARY_VERSION {
+            // the header layout may differ between versions,
+            // so there's no telling where the next record starts
+            let e = ParseError::UnsupportedVersion { record, version };
+            return match mode {
+                ParseMode::Strict => Err(e),
+                ParseMode::Lenient => {
+                    frame.diagnostics.push(e);
+                    Ok(input.len())
+                }
+            };
+        }
+        let record_type = header[1];
+        let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+
+        let start = offset + HEADER_LEN;
+        let Some(payload) = input.get(start..start + len) else {
+            break;
+        };
+        match decode_record(record, record_type, payload) {
+            Ok(event) => frame.events.push(event),
+            Err(e) => match mode {
+                ParseMode::Strict => return Err(e),
+                ParseMode::Lenient => frame.diagnostics.push(e),
+            },
+        }
+
+        offset = start + len;
+        record += 1;
+    }
+
+    Ok(offset)
+}
+
+fn decode_record(record: usize, record_type: u8, payload: &[u8]) -> Result<ToucheData, ParseError> {
+    let expect = |expected: usize| {
+        payload.get(..expected).ok_or(ParseError::TruncatedRecord {
+            record,
+            record_type,
+            len: payload.len(),
+            expected,
+        })
+    };
+
+    let data = match record_type {
+        RECORD_SCREEN_SIZE => {
+            let p = expect(8)?;
+            ToucheData::ScreenSize {
+                x: read_i32(p, 0),
+                y: read_i32(p, 4),
+            }
+        }
+        RECORD_STYLUS => {
+            let p = expect(STYLUS_LEN)?;
+            let flags = p[8];
+            let pressed = flags & FLAG_PRESSED != 0;
+            let extended = payload.get(..STYLUS_EXTENDED_LEN);
+            let extra = |flag: u8, at: usize| {
+                extended
+                    .filter(|_| flags & flag != 0)
+                    .map(|p| read_f32(p, at))
+            };
+            ToucheData::StylusFrame {
+                x: read_f32(p, 0),
+                y: read_f32(p, 4),
+                pressed,
+                pressure: (pressed && flags & FLAG_HAS_PRESSURE != 0).then(|| read_f32(p, 9)),
+                tilt_x: extra(FLAG_HAS_TILT, 13),
+                tilt_y: extra(FLAG_HAS_TILT, 17),
+                orientation: extra(FLAG_HAS_ORIENTATION, 21),
+                distance: extra(FLAG_HAS_DISTANCE, 25),
+                tool: if flags & FLAG_ERASER != 0 {
+                    StylusTool::Eraser
+                } else {
+                    StylusTool::Pen
+                },
+                primary_button: flags & FLAG_PRIMARY_BUTTON != 0,
+                secondary_button: flags & FLAG_SECONDARY_BUTTON != 0,
+                in_proximity: payload
+                    .get(..STYLUS_STATE_LEN)
+                    .is_none_or(|p| p[29] & STATE_OUT_OF_PROXIMITY == 0),
+            }
+        }
+        RECORD_TOUCH => {
+            let p = expect(TOUCH_LEN)?;
+            ToucheData::TouchFrame {
+                x: read_f32(p, 0),
+                y: read_f32(p, 4),
+                touch_id: read_i32(p, 8),
+                pressed: p[12] & FLAG_PRESSED != 0,
+            }
+        }
+        RECORD_ROTATION => {
+            let p = expect(ROTATION_LEN)?;
+            let degrees = u16::from_le_bytes([p[0], p[1]]);
+            match Rotation::from_degrees(degrees.into()) {
+                Some(rotation) => ToucheData::Rotation(rotation),
+                None => return Err(ParseError::InvalidRotation { record, degrees }),
+            }
+        }
+        _ => {
+            return Err(ParseError::UnknownRecordType {
+                record,
+                record_type,
+            });
+        }
+    };
+
+    Ok(data)
+}
+
+fn read_i32(payload: &[u8], at: usize) -> i32 {
+    i32::from_le_bytes([
+        payload[at],
+        payload[at + 1],
+        payload[at + 2],
+        payload[at + 3],
+    ])
+}
+
+fn read_f32(payload: &[u8], at: usize) -> f32 {
+    f32::from_le_bytes([
+        payload[at],
+        payload[at + 1],
+        payload[at + 2],
+        payload[at + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(record_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut record = vec![BINARY_VERSION, record_type];
+        record.extend_from_slice(&u16::try_from(payload.len()).unwrap().to_le_bytes());
+        record.extend_from_slice(payload);
+        record
+    }
+
+    fn floats(values: &[f32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    /// A stylus payload with every optional field, and `state` if there is one.
+    fn stylus(flags: u8, state: Option<u8>) -> Vec<u8> {
+        let mut payload = floats(&[100.0, 200.0]);
+        payload.push(flags);
+        payload.extend(floats(&[0.5, 10.0, -20.0, 45.0, 0.25]));
+        payload.extend(state);
+        payload
+    }
+
+    fn decode(input: &[u8], mode: ParseMode) -> Result<(usize, ParsedFrame), ParseError> {
+        let mut frame = ParsedFrame::default();
+        let consumed = decode_records(input, mode, &mut frame)?;
+        Ok((consumed, frame))
+    }
+
+    #[test]
+    fn decodes_every_optional_stylus_field_its_flag_asks_for() {
+        let flags = FLAG_PRESSED
+            | FLAG_HAS_PRESSURE
+            | FLAG_HAS_TILT
+            | FLAG_HAS_ORIENTATION
+            | FLAG_HAS_DISTANCE
+            | FLAG_PRIMARY_BUTTON
+            | FLAG_ERASER;
+        let (_, frame) = decode(
+            &record(RECORD_STYLUS, &stylus(flags, None)),
+            ParseMode::Strict,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            frame.events[..],
+            [ToucheData::StylusFrame {
+                x: 100.0,
+                y: 200.0,
+                pressed: true,
+                pressure: Some(0.5),
+                tilt_x: Some(10.0),
+                tilt_y: Some(-20.0),
+                orientation: Some(45.0),
+                distance: Some(0.25),
+                tool: StylusTool::Eraser,
+                primary_button: true,
+                secondary_button: false,
+                in_proximity: true,
+            }]
+        ));
+    }
+
+    #[test]
+    fn leaves_out_stylus_fields_without_their_flag() {
+        let (_, frame) = decode(
+            &record(
+                RECORD_STYLUS,
+                &stylus(FLAG_HAS_PRESSURE | FLAG_SECONDARY_BUTTON, None),
+            ),
+            ParseMode::Strict,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            frame.events[..],
+            [ToucheData::StylusFrame {
+                pressed: false,
+                // pressure only counts while pressed
+                pressure: None,
+                tilt_x: None,
+                tilt_y: None,
+                orientation: None,
+                distance: None,
+                tool: StylusTool::Pen,
+                primary_button: false,
+                secondary_button: true,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn reads_the_proximity_state() {
+        let (_, frame) = decode(
+            &record(RECORD_STYLUS, &stylus(0, Some(STATE_OUT_OF_PROXIMITY))),
+            ParseMode::Strict,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            frame.events[..],
+            [ToucheData::StylusFrame {
+                in_proximity: false,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn skips_fields_it_doesnt_know_yet() {
+        let mut payload = floats(&[1.0, 2.0]);
+        payload.extend_from_slice(&7_i32.to_le_bytes());
+        payload.push(FLAG_PRESSED);
+        payload.extend_from_slice(b"from the future");
+        let mut input = record(RECORD_TOUCH, &payload);
+        input.extend(record(RECORD_SCREEN_SIZE, &[0x38, 4, 0, 0, 0x60, 9, 0, 0]));
+
+        let (consumed, frame) = decode(&input, ParseMode::Strict).unwrap();
+
+        assert_eq!(consumed, input.len());
+        assert!(matches!(
+            frame.events[..],
+            [
+                ToucheData::TouchFrame {
+                    x: 1.0,
+                    y: 2.0,
+                    touch_id: 7,
+                    pressed: true
+                },
+                ToucheData::ScreenSize { x: 1080, y: 2400 },
+            ]
+        ));
+    }
+
+    #[test]
+    fn stops_at_a_record_that_hasnt_fully_arrived() {
+        let mut input = record(RECORD_ROTATION, &90_u16.to_le_bytes());
+        let complete = input.len();
+        input.extend(&record(RECORD_TOUCH, &[0; TOUCH_LEN])[..6]);
+
+        let (consumed, frame) = decode(&input, ParseMode::Strict).unwrap();
+
+        assert_eq!(consumed, complete);
+        assert!(matches!(
+            frame.events[..],
+            [ToucheData::Rotation(Rotation::R90)]
+        ));
+    }
+
+    #[test]
+    fn strict_fails_on_a_broken_record() {
+        let result = decode(&record(RECORD_TOUCH, &[0; 4]), ParseMode::Strict);
+
+        assert!(matches!(
+            result,
+            Err(ParseError::TruncatedRecord {
+                record: 0,
+                record_type: RECORD_TOUCH,
+                len: 4,
+                expected: TOUCH_LEN
+            })
+        ));
+    }
+
+    #[test]
+    fn lenient_skips_broken_records() {
+        let mut input = record(0x7f, &[1, 2, 3]);
+        input.extend(record(RECORD_ROTATION, &45_u16.to_le_bytes()));
+        input.extend(record(RECORD_SCREEN_SIZE, &[1, 0, 0, 0, 2, 0, 0, 0]));
+
+        let (consumed, frame) = decode(&input, ParseMode::Lenient).unwrap();
+
+        assert_eq!(consumed, input.len());
+        assert!(matches!(
+            frame.events[..],
+            [ToucheData::ScreenSize { x: 1, y: 2 }]
+        ));
+        assert!(matches!(
+            frame.diagnostics[..],
+            [
+                ParseError::UnknownRecordType {
+                    record: 0,
+                    record_type: 0x7f
+                },
+                ParseError::InvalidRotation {
+                    record: 1,
+                    degrees: 45
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn gives_up_on_an_unknown_version() {
+        let mut input = record(RECORD_ROTATION, &[0, 0]);
+        input[0] = BINARY_VERSION + 1;
+
+        assert!(matches!(
+            decode(&input, ParseMode::Strict),
+            Err(ParseError::UnsupportedVersion { record: 0, version }) if version == BINARY_VERSION + 1
+        ));
+        // there's no telling where the next record starts, so all of it goes
+        let (consumed, frame) = decode(&input, ParseMode::Lenient).unwrap();
+        assert_eq!(consumed, input.len());
+        assert!(frame.events.is_empty());
+    }
+}
@@ -0,0 +1,84 @@
+//! What each side of the connection can do.
+//!
+//! During the handshake the phone answers the handshake opcode with an `X`
+//! row, a `C` row and, if it isn't held upright, an `R` row:
+//!
+//! ```text
+//! C <protocol version> <feature bits> <max touches> <frame rate>
+//! ```
+//!
+//! The host then sends its own [`Capabilities`] in a message of their own,
+//! so both sides know what was agreed on. Phones that predate the handshake
+//! only send `X`, get [`Capabilities::LEGACY`] and never see that message.
+
+use super::binary::BINARY_VERSION;
+
+pub(crate) const FEATURE_STYLUS: u8 = 1 << 0;
+pub(crate) const FEATURE_PRESSURE: u8 = 1 << 1;
+pub(crate) const FEATURE_TILT: u8 = 1 << 2;
+pub(crate) const FEATURE_HOVER: u8 = 1 << 3;
+pub(crate) const FEATURE_BUTTONS: u8 = 1 << 4;
+pub(crate) const FEATURE_ERASER: u8 = 1 << 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Capabilities {
+    /// highest binary protocol version, 0 means text only
+    pub(crate) protocol_version: u8,
+    /// `FEATURE_*` bits
+    pub(crate) features: u8,
+    /// how many fingers can be tracked at once, 0 means no touch input at all
+    pub(crate) max_touches: u8,
+    /// frames per second
+    pub(crate) frame_rate: u16,
+}
+
+impl Capabilities {
+    /// Everything this driver knows how to turn into input events.
+    pub(crate) const HOST: Capabilities = Capabilities {
+        protocol_version: BINARY_VERSION,
+        features: FEATURE_STYLUS
+            | FEATURE_PRESSURE
+            | FEATURE_TILT
+            | FEATURE_HOVER
+            | FEATURE_BUTTONS
+            | FEATURE_ERASER,
+        max_touches: 10,
+        frame_rate: 200,
+    };
+
+    /// What a phone without a `C` row is assumed to do: the feature set of
+    /// the app versions released before the handshake existed. The tablet
+    /// always had a distance axis back then, so hover is part of it.
+    pub(crate) const LEGACY: Capabilities = Capabilities {
+        protocol_version: 0,
+        features: FEATURE_STYLUS | FEATURE_PRESSURE | FEATURE_HOVER,
+        max_touches: 10,
+        frame_rate: 200,
+    };
+
+    pub(crate) fn has(&self, feature: u8) -> bool {
+        self.features & feature == feature
+    }
+
+    /// The subset both sides agree on.
+    pub(crate) fn negotiate(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            protocol_version: self.protocol_version.min(other.protocol_version),
+            features: self.features & other.features,
+            max_touches: self.max_touches.min(other.max_touches),
+            frame_rate: self.frame_rate.min(other.frame_rate).max(1),
+        }
+    }
+
+    /// Wire representation sent to the phone after its handshake reply.
+    pub(crate) fn to_bytes(self) -> [u8; 5] {
+        let [rate_low, rate_high] = self.frame_rate.to_le_bytes();
+        [
+            self.protocol_version,
+            self.features,
+            self.max_touches,
+            rate_low,
+            rate_high,
+        ]
+    }
+}
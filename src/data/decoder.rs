@@ -0,0 +1,176 @@
+use log::warn;
+
+use super::{ParseError, ParseMode, ParsedFrame, binary, parse_touche_data};
+
+/// How many bytes of an unfinished record we are willing to hold on to.
+/// A sane record is a few dozen bytes long, so anything past this is garbage.
+pub(crate) const DEFAULT_MAX_PENDING: usize = 64 * 1024;
+
+/// Which flavour of the protocol the phone agreed to send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum WireFormat {
+    /// tab-separated `X`/`S`/`F` rows, one per line
+    Text,
+    /// length-prefixed records, see [`binary`]
+    Binary,
+}
+
+/// Stateful decoder for the touche protocol.
+///
+/// USB bulk transfers don't care about record boundaries, so a single record
+/// may arrive split across two reads. The decoder keeps the unfinished tail
+/// of every transfer and only decodes complete records: newline-terminated
+/// rows for [`WireFormat::Text`], fully received payloads for
+/// [`WireFormat::Binary`].
+pub(crate) struct ToucheDecoder {
+    pending: Vec<u8>,
+    max_pending: usize,
+    format: WireFormat,
+    mode: ParseMode,
+}
+
+impl ToucheDecoder {
+    pub(crate) fn new(format: WireFormat, mode: ParseMode) -> ToucheDecoder {
+        ToucheDecoder::with_max_pending(format, mode, DEFAULT_MAX_PENDING)
+    }
+
+    pub(crate) fn with_max_pending(
+        format: WireFormat,
+        mode: ParseMode,
+        max_pending: usize,
+    ) -> ToucheDecoder {
+        ToucheDecoder {
+            pending: vec![],
+            max_pending,
+            format,
+            mode,
+        }
+    }
+
+    /// Feeds one transfer worth of bytes and returns every record completed by it.
+    pub(crate) fn feed(&mut self, bytes: &[u8]) -> Result<ParsedFrame, ParseError> {
+        self.pending.extend_from_slice(bytes);
+
+        let mut frame = match self.format {
+            WireFormat::Text => self.decode_text()?,
+            WireFormat::Binary => {
+                let mut frame = ParsedFrame::default();
+                let consumed = binary::decode_records(&self.pending, self.mode, &mut frame)?;
+                self.pending.drain(..consumed);
+                frame
+            }
+        };
+
+        if self.pending.len() > self.max_pending {
+            let e = ParseError::BufferOverflow {
+                len: self.pending.len(),
+                max: self.max_pending,
+            };
+            warn!("dropping unterminated data: {}", e);
+            self.pending.clear();
+            match self.mode {
+                ParseMode::Strict => return Err(e),
+                ParseMode::Lenient => frame.diagnostics.push(e),
+            }
+        }
+
+        Ok(frame)
+    }
+
+    /// Whether part of a record is waiting for the rest of it.
+    pub(crate) fn is_mid_record(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn decode_text(&mut self) -> Result<ParsedFrame, ParseError> {
+        match self.pending.iter().rposition(|byte| *byte == b'\n') {
+            Some(last_newline) => {
+                let rest = self.pending.split_off(last_newline + 1);
+                let complete = std::mem::replace(&mut self.pending, rest);
+                parse_touche_data(&complete, self.mode)
+            }
+            None => Ok(ParsedFrame::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{ToucheData, binary::BINARY_VERSION};
+
+    /// A pressed pen at `x`, `y`, as a binary stylus record.
+    fn stylus_record(x: f32, y: f32, pressure: f32) -> Vec<u8> {
+        let mut record = vec![BINARY_VERSION, 0x02, 13, 0];
+        record.extend_from_slice(&x.to_le_bytes());
+        record.extend_from_slice(&y.to_le_bytes());
+        // pressed, with pressure
+        record.push(0b11);
+        record.extend_from_slice(&pressure.to_le_bytes());
+        record
+    }
+
+    fn is_pen_at(event: &ToucheData, at: (f32, f32), with: f32) -> bool {
+        matches!(
+            event,
+            ToucheData::StylusFrame { x, y, pressed: true, pressure: Some(pressure), .. }
+                if (*x, *y) == at && *pressure == with
+        )
+    }
+
+    #[test]
+    fn joins_rows_split_across_transfers() {
+        let mut decoder = ToucheDecoder::new(WireFormat::Text, ParseMode::Strict);
+
+        let first = decoder.feed(b"S\t100\t2").unwrap();
+        let second = decoder.feed(b"00\t1\t0.5\nF\t10\t20\t1").unwrap();
+        let third = decoder.feed(b"\t0\n").unwrap();
+
+        assert!(first.events.is_empty());
+        assert_eq!(second.events.len(), 1);
+        assert!(is_pen_at(&second.events[0], (100.0, 200.0), 0.5));
+        assert!(matches!(
+            third.events[..],
+            [ToucheData::TouchFrame {
+                x: 10.0,
+                y: 20.0,
+                touch_id: 0,
+                pressed: true
+            }]
+        ));
+    }
+
+    #[test]
+    fn joins_records_split_across_transfers() {
+        let mut decoder = ToucheDecoder::new(WireFormat::Binary, ParseMode::Strict);
+        let mut bytes = stylus_record(100.0, 200.0, 0.5);
+        bytes.extend(stylus_record(110.0, 210.0, 0.75));
+
+        // in the middle of a header, then in the middle of a payload
+        let first = decoder.feed(&bytes[..2]).unwrap();
+        let second = decoder.feed(&bytes[2..10]).unwrap();
+        let third = decoder.feed(&bytes[10..25]).unwrap();
+        let fourth = decoder.feed(&bytes[25..]).unwrap();
+
+        assert!(first.events.is_empty());
+        assert!(second.events.is_empty());
+        assert_eq!(third.events.len(), 1);
+        assert!(is_pen_at(&third.events[0], (100.0, 200.0), 0.5));
+        assert_eq!(fourth.events.len(), 1);
+        assert!(is_pen_at(&fourth.events[0], (110.0, 210.0), 0.75));
+    }
+
+    #[test]
+    fn gives_up_on_a_row_that_never_ends() {
+        let mut decoder = ToucheDecoder::with_max_pending(WireFormat::Text, ParseMode::Lenient, 8);
+
+        let frame = decoder.feed(b"S\t100\t200\t1").unwrap();
+
+        assert!(matches!(
+            frame.diagnostics[..],
+            [ParseError::BufferOverflow { len: 11, max: 8 }]
+        ));
+        // and starts over with what comes next
+        assert_eq!(decoder.feed(b"F\t1\t2\t1\t0\n").unwrap().events.len(), 1);
+    }
+}
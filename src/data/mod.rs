@@ -1,6 +1,13 @@
-use std::string::FromUtf8Error;
+pub(crate) mod binary;
+pub(crate) mod capabilities;
+pub(crate) mod decoder;
+pub(crate) mod rotation;
 
+use std::{fmt, str::FromStr};
+
+use capabilities::Capabilities;
 use log::trace;
+use rotation::Rotation;
 
 pub(crate) enum ToucheData {
     ScreenSize {
@@ -8,126 +15,421 @@ pub(crate) enum ToucheData {
         y: i32,
     },
     StylusFrame {
-        x: i32,
-        y: i32,
+        x: f32,
+        y: f32,
         pressed: bool,
         pressure: Option<f32>,
+        /// degrees, -90..=90, positive towards the right edge
+        tilt_x: Option<f32>,
+        /// degrees, -90..=90, positive towards the bottom edge
+        tilt_y: Option<f32>,
+        /// azimuth of the pen around its own axis, degrees, -180..=180
+        orientation: Option<f32>,
+        /// hover distance, 0.0 (touching) ..= 1.0 (edge of proximity)
+        distance: Option<f32>,
+        tool: StylusTool,
+        /// lower barrel button, the only one on an S-Pen
+        primary_button: bool,
+        /// upper barrel button
+        secondary_button: bool,
+        /// `false` once the pen has moved out of hover range
+        in_proximity: bool,
     },
     TouchFrame {
-        x: i32,
-        y: i32,
+        x: f32,
+        y: f32,
         touch_id: i32,
         pressed: bool,
     },
+    /// what the phone can do, sent during the handshake
+    Capabilities(Capabilities),
+    /// the phone has been turned, sent during the handshake and whenever it changes
+    Rotation(Rotation),
 }
 
-pub(crate) fn parse_touche_data(input: &Vec<u8>) -> Result<Vec<ToucheData>, FromUtf8Error> {
-    let info_string = String::from_utf8(input.to_owned())?;
-    trace!("touche input data:\n{}", info_string);
-
-    let token_table: Vec<Vec<&str>> = info_string
-        .split("\n")
-        .map(|it| it.split("\t").collect::<Vec<&str>>())
-        .collect();
-
-    let mut data: Vec<ToucheData> = vec![];
-    for token_row in token_table {
-        match token_row[0] {
-            "X" => {
-                let x = token_row[1].parse::<i32>();
-                if x.is_err() {
-                    continue;
-                }
-                let x = x.unwrap();
+/// Which end of the pen is pointed at the screen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum StylusTool {
+    #[default]
+    Pen,
+    Eraser,
+}
+
+/// Everything that can go wrong with a single row of a touche frame.
+///
+/// `row` is the zero-based line index inside the frame, `tag` is the
+/// record tag (`X`, `S`, `F`, `C` or `R`) and `field` is the column that failed.
+/// Binary records have no tag or columns, so their variants carry the
+/// record index and the numeric record type instead.
+/// `BufferOverflow` is the odd one out: it means the sender never finished
+/// its record and the decoder gave up waiting.
+#[derive(Debug)]
+pub(crate) enum ParseError {
+    InvalidUtf8 {
+        row: usize,
+    },
+    UnknownTag {
+        row: usize,
+        tag: String,
+    },
+    MissingField {
+        row: usize,
+        tag: &'static str,
+        field: &'static str,
+    },
+    InvalidNumber {
+        row: usize,
+        tag: &'static str,
+        field: &'static str,
+        value: String,
+    },
+    UnsupportedVersion {
+        record: usize,
+        version: u8,
+    },
+    UnknownRecordType {
+        record: usize,
+        record_type: u8,
+    },
+    TruncatedRecord {
+        record: usize,
+        record_type: u8,
+        len: usize,
+        expected: usize,
+    },
+    InvalidRotation {
+        record: usize,
+        degrees: u16,
+    },
+    BufferOverflow {
+        len: usize,
+        max: usize,
+    },
+}
 
-                let y = token_row[2].parse::<i32>();
-                if y.is_err() {
-                    continue;
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidUtf8 { row } => write!(f, "row {}: not valid UTF-8", row),
+            ParseError::UnknownTag { row, tag } => {
+                write!(f, "row {}: unknown record tag `{}`", row, tag)
+            }
+            ParseError::MissingField { row, tag, field } => {
+                write!(f, "row {} ({}): missing field `{}`", row, tag, field)
+            }
+            ParseError::InvalidNumber {
+                row,
+                tag,
+                field,
+                value,
+            } => write!(
+                f,
+                "row {} ({}): `{}` is not a valid number for field `{}`",
+                row, tag, value, field
+            ),
+            ParseError::UnsupportedVersion { record, version } => write!(
+                f,
+                "record {}: unsupported binary protocol version {}",
+                record, version
+            ),
+            ParseError::UnknownRecordType {
+                record,
+                record_type,
+            } => write!(
+                f,
+                "record {}: unknown record type {:#04x}",
+                record, record_type
+            ),
+            ParseError::TruncatedRecord {
+                record,
+                record_type,
+                len,
+                expected,
+            } => write!(
+                f,
+                "record {} ({:#04x}): payload is {} bytes, expected at least {}",
+                record, record_type, len, expected
+            ),
+            ParseError::InvalidRotation { record, degrees } => write!(
+                f,
+                "record {}: rotation of {} degrees, expected 0, 90, 180 or 270",
+                record, degrees
+            ),
+            ParseError::BufferOverflow { len, max } => write!(
+                f,
+                "{} bytes of unfinished data, over the limit of {}",
+                len, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ParseMode {
+    /// the first broken row fails the whole frame
+    // everything the driver reads comes from phones it has to get along with,
+    // so for now only the tests are this picky
+    #[cfg_attr(not(test), allow(dead_code))]
+    Strict,
+    /// broken rows are skipped and reported in [`ParsedFrame::diagnostics`]
+    Lenient,
+}
+
+#[derive(Default)]
+pub(crate) struct ParsedFrame {
+    pub(crate) events: Vec<ToucheData>,
+    pub(crate) diagnostics: Vec<ParseError>,
+}
+
+pub(crate) fn parse_touche_data(input: &[u8], mode: ParseMode) -> Result<ParsedFrame, ParseError> {
+    trace!("touche input data:\n{}", String::from_utf8_lossy(input));
+
+    let mut frame = ParsedFrame::default();
+    for (row, line) in input.split(|byte| *byte == b'\n').enumerate() {
+        match parse_row(row, line) {
+            Ok(Some(event)) => frame.events.push(event),
+            Ok(None) => {}
+            Err(e) => match mode {
+                ParseMode::Strict => return Err(e),
+                ParseMode::Lenient => {
+                    trace!("skipping broken row: {}", e);
+                    frame.diagnostics.push(e);
                 }
-                let y = y.unwrap();
+            },
+        }
+    }
+
+    Ok(frame)
+}
 
-                data.push(ToucheData::ScreenSize { x, y });
+fn parse_row(row: usize, line: &[u8]) -> Result<Option<ToucheData>, ParseError> {
+    let line = std::str::from_utf8(line).map_err(|_| ParseError::InvalidUtf8 { row })?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let tokens: Vec<&str> = line.split('\t').collect();
+
+    let data = match tokens[0] {
+        "X" => {
+            let field = Fields::new(row, "X", &tokens);
+            ToucheData::ScreenSize {
+                x: field.parse::<i32>(1, "x")?,
+                y: field.parse::<i32>(2, "y")?,
             }
-            "S" => {
-                if let Some([x, y, pressed]) = token_row.get(1..=3) {
-                    let x = match x.parse::<f32>() {
-                        Ok(x) => x,
-                        Err(_) => continue,
-                    };
-
-                    let y = match y.parse::<f32>() {
-                        Ok(x) => x,
-                        Err(_) => continue,
-                    };
-
-                    let pressed = match pressed.parse::<i32>() {
-                        Ok(x) => x,
-                        Err(_) => continue,
-                    };
-                    let pressed = pressed == 1;
-
-                    let pressure = if pressed {
-                        match token_row.get(4) {
-                            Some(pressure) => match pressure.parse::<f32>() {
-                                Ok(pressure) => Some(pressure),
-                                Err(_) => {
-                                    continue;
-                                },
-                            },
-                            None => None,
-                        }
-                    } else {
-                        None
-                    };
-                    data.push(ToucheData::StylusFrame {
-                        x: x as i32,
-                        y: y as i32,
-                        pressed,
-                        pressure,
-                    });
-                };
+        }
+        "S" => {
+            let field = Fields::new(row, "S", &tokens);
+            let x = field.parse::<f32>(1, "x")?;
+            let y = field.parse::<f32>(2, "y")?;
+            let pressed = field.parse::<i32>(3, "pressed")? == 1;
+            let buttons = field.parse_optional::<u8>(9, "buttons")?.unwrap_or(0);
+            let pressure = if pressed {
+                field.parse_optional::<f32>(4, "pressure")?
+            } else {
+                None
+            };
+            ToucheData::StylusFrame {
+                x,
+                y,
+                pressed,
+                pressure,
+                tilt_x: field.parse_optional::<f32>(5, "tilt_x")?,
+                tilt_y: field.parse_optional::<f32>(6, "tilt_y")?,
+                orientation: field.parse_optional::<f32>(7, "orientation")?,
+                distance: field.parse_optional::<f32>(8, "distance")?,
+                tool: match field.parse_optional::<u8>(10, "tool")? {
+                    Some(1) => StylusTool::Eraser,
+                    _ => StylusTool::Pen,
+                },
+                primary_button: buttons & 0b01 != 0,
+                secondary_button: buttons & 0b10 != 0,
+                in_proximity: field.parse_optional::<i32>(11, "proximity")? != Some(0),
             }
-            "F" => {
-                if let Some([x, y, pressed, touch_id]) = token_row.get(1..=4) {
-                    let x = x.parse::<f32>();
-                    if x.is_err() {
-                        trace!("error parsing F.x");
-                        continue;
-                    }
-                    let x = x.unwrap();
-
-                    let y = y.parse::<f32>();
-                    if y.is_err() {
-                        trace!("error parsing F.y");
-                        continue;
-                    }
-                    let y = y.unwrap();
-
-                    let pressed = pressed.parse::<i32>();
-                    if pressed.is_err() {
-                        trace!("error parsing F.pressed");
-                        continue;
-                    }
-                    let pressed = pressed.unwrap() == 1;
-
-                    let touch_id = touch_id.parse();
-                    if touch_id.is_err() {
-                        trace!("error parsing F.touch_id");
-                        continue;
-                    }
-                    let touch_id = touch_id.unwrap();
-
-                    data.push(ToucheData::TouchFrame {
-                        x: x as i32,
-                        y: y as i32,
-                        touch_id,
-                        pressed
-                    });
-                };
+        }
+        "F" => {
+            let field = Fields::new(row, "F", &tokens);
+            let x = field.parse::<f32>(1, "x")?;
+            let y = field.parse::<f32>(2, "y")?;
+            let pressed = field.parse::<i32>(3, "pressed")? == 1;
+            let touch_id = field.parse::<i32>(4, "touch_id")?;
+            ToucheData::TouchFrame {
+                x,
+                y,
+                touch_id,
+                pressed,
             }
-            _ => {}
         }
+        "C" => {
+            let field = Fields::new(row, "C", &tokens);
+            ToucheData::Capabilities(Capabilities {
+                protocol_version: field.parse::<u8>(1, "protocol_version")?,
+                features: field.parse::<u8>(2, "features")?,
+                max_touches: field.parse::<u8>(3, "max_touches")?,
+                frame_rate: field.parse::<u16>(4, "frame_rate")?,
+            })
+        }
+        "R" => {
+            let field = Fields::new(row, "R", &tokens);
+            let degrees = field.parse::<i32>(1, "degrees")?;
+            ToucheData::Rotation(Rotation::from_degrees(degrees).ok_or(
+                ParseError::InvalidNumber {
+                    row,
+                    tag: "R",
+                    field: "degrees",
+                    value: degrees.to_string(),
+                },
+            )?)
+        }
+        tag => {
+            return Err(ParseError::UnknownTag {
+                row,
+                tag: tag.to_owned(),
+            });
+        }
+    };
+
+    Ok(Some(data))
+}
+
+/// Columns of a single row, with enough context to build a [`ParseError`].
+struct Fields<'a> {
+    row: usize,
+    tag: &'static str,
+    tokens: &'a [&'a str],
+}
+
+impl<'a> Fields<'a> {
+    fn new(row: usize, tag: &'static str, tokens: &'a [&'a str]) -> Fields<'a> {
+        Fields { row, tag, tokens }
+    }
+
+    fn parse<T: FromStr>(&self, idx: usize, field: &'static str) -> Result<T, ParseError> {
+        self.parse_optional(idx, field)?
+            .ok_or(ParseError::MissingField {
+                row: self.row,
+                tag: self.tag,
+                field,
+            })
     }
 
-    Result::Ok(data)
+    fn parse_optional<T: FromStr>(
+        &self,
+        idx: usize,
+        field: &'static str,
+    ) -> Result<Option<T>, ParseError> {
+        // trailing columns are optional, and so are empty ones,
+        // so a phone can skip pressure while still sending tilt
+        match self.tokens.get(idx).filter(|value| !value.is_empty()) {
+            Some(value) => value
+                .parse::<T>()
+                .map(Some)
+                .map_err(|_| ParseError::InvalidNumber {
+                    row: self.row,
+                    tag: self.tag,
+                    field,
+                    value: (*value).to_owned(),
+                }),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_row() {
+        let frame = parse_touche_data(
+            b"X\t1080\t2400\nS\t10.5\t20\t1\t0.5\nF\t1\t2\t0\t3\n",
+            ParseMode::Strict,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            frame.events[..],
+            [
+                ToucheData::ScreenSize { x: 1080, y: 2400 },
+                ToucheData::StylusFrame {
+                    x: 10.5,
+                    y: 20.0,
+                    pressed: true,
+                    pressure: Some(0.5),
+                    ..
+                },
+                ToucheData::TouchFrame {
+                    x: 1.0,
+                    y: 2.0,
+                    touch_id: 3,
+                    pressed: false
+                },
+            ]
+        ));
+        assert!(frame.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn ignores_empty_rows() {
+        let frame = parse_touche_data(b"\n\nX\t1\t2\n\n", ParseMode::Strict).unwrap();
+
+        assert_eq!(frame.events.len(), 1);
+    }
+
+    #[test]
+    fn strict_fails_on_the_first_broken_row() {
+        let result = parse_touche_data(b"X\t1\t2\nQ\t1\nS\tnope\t2\t1\n", ParseMode::Strict);
+
+        assert!(matches!(
+            result,
+            Err(ParseError::UnknownTag { row: 1, ref tag }) if tag == "Q"
+        ));
+    }
+
+    #[test]
+    fn lenient_skips_broken_rows_and_says_why() {
+        let frame = parse_touche_data(
+            b"Q\t1\nS\tnope\t2\t1\nF\t1\t2\t1\nX\t1\t2\n\xff\n",
+            ParseMode::Lenient,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            frame.events[..],
+            [ToucheData::ScreenSize { x: 1, y: 2 }]
+        ));
+        assert!(matches!(
+            &frame.diagnostics[..],
+            [
+                ParseError::UnknownTag { row: 0, .. },
+                ParseError::InvalidNumber {
+                    row: 1,
+                    tag: "S",
+                    field: "x",
+                    value,
+                },
+                ParseError::MissingField {
+                    row: 2,
+                    tag: "F",
+                    field: "touch_id"
+                },
+                ParseError::InvalidUtf8 { row: 4 },
+            ] if value == "nope"
+        ));
+    }
+
+    #[test]
+    fn pressure_only_counts_while_pressed() {
+        let frame = parse_touche_data(b"S\t1\t2\t0\t0.5\n", ParseMode::Strict).unwrap();
+
+        assert!(matches!(
+            frame.events[..],
+            [ToucheData::StylusFrame {
+                pressed: false,
+                pressure: None,
+                ..
+            }]
+        ));
+    }
 }
@@ -0,0 +1,149 @@
+use serde::Deserialize;
+
+/// How far the reported coordinates have to be turned, clockwise, to be
+/// upright for the person holding the phone.
+///
+/// An app that rotates its own UI reports upright coordinates and stays at
+/// [`Rotation::R0`]. An app locked to portrait while the phone lies sideways
+/// reports [`Rotation::R90`] or [`Rotation::R270`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "i32")]
+pub(crate) enum Rotation {
+    #[default]
+    R0,
+    R90,
+    R180,
+    R270,
+}
+
+impl Rotation {
+    /// Any multiple of 90, negative ones included.
+    pub(crate) fn from_degrees(degrees: i32) -> Option<Rotation> {
+        match degrees.rem_euclid(360) {
+            0 => Some(Rotation::R0),
+            90 => Some(Rotation::R90),
+            180 => Some(Rotation::R180),
+            270 => Some(Rotation::R270),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn degrees(self) -> i32 {
+        match self {
+            Rotation::R0 => 0,
+            Rotation::R90 => 90,
+            Rotation::R180 => 180,
+            Rotation::R270 => 270,
+        }
+    }
+
+    /// Size of a `width` x `height` screen once rotated.
+    pub(crate) fn size(self, width: i32, height: i32) -> (i32, i32) {
+        match self {
+            Rotation::R0 | Rotation::R180 => (width, height),
+            Rotation::R90 | Rotation::R270 => (height, width),
+        }
+    }
+
+    /// Rotates a point on a `width` x `height` screen.
+    pub(crate) fn point(self, x: f32, y: f32, width: f32, height: f32) -> (f32, f32) {
+        match self {
+            Rotation::R0 => (x, y),
+            Rotation::R90 => (height - y, x),
+            Rotation::R180 => (width - x, height - y),
+            Rotation::R270 => (y, width - x),
+        }
+    }
+
+    /// Rotates a direction, such as a `(tilt_x, tilt_y)` pair.
+    pub(crate) fn vector(self, x: f32, y: f32) -> (f32, f32) {
+        match self {
+            Rotation::R0 => (x, y),
+            Rotation::R90 => (-y, x),
+            Rotation::R180 => (-x, -y),
+            Rotation::R270 => (y, -x),
+        }
+    }
+
+    /// Rotates an angle in degrees, keeping it within -180..=180.
+    pub(crate) fn angle(self, degrees: f32) -> f32 {
+        let rotated = (degrees + self.degrees() as f32).rem_euclid(360.0);
+        if rotated > 180.0 {
+            rotated - 360.0
+        } else {
+            rotated
+        }
+    }
+}
+
+impl TryFrom<i32> for Rotation {
+    type Error = String;
+
+    fn try_from(degrees: i32) -> Result<Self, Self::Error> {
+        Rotation::from_degrees(degrees).ok_or(format!("{} is not a multiple of 90", degrees))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Rotation; 4] = [Rotation::R0, Rotation::R90, Rotation::R180, Rotation::R270];
+
+    #[test]
+    fn takes_any_multiple_of_90() {
+        assert_eq!(Rotation::from_degrees(450), Some(Rotation::R90));
+        assert_eq!(Rotation::from_degrees(-90), Some(Rotation::R270));
+        assert_eq!(Rotation::from_degrees(45), None);
+        assert!(Rotation::try_from(100).is_err());
+    }
+
+    #[test]
+    fn corners_stay_corners() {
+        // top left of a 100x50 screen, once it is upright
+        let corners = ALL.map(|rotation| rotation.point(0.0, 0.0, 100.0, 50.0));
+
+        assert_eq!(
+            corners,
+            [(0.0, 0.0), (50.0, 0.0), (100.0, 50.0), (0.0, 100.0)]
+        );
+    }
+
+    #[test]
+    fn points_land_on_the_rotated_screen() {
+        for rotation in ALL {
+            let (width, height) = rotation.size(100, 50);
+            let (x, y) = rotation.point(10.0, 40.0, 100.0, 50.0);
+
+            assert!((0.0..=width as f32).contains(&x), "{:?}", rotation);
+            assert!((0.0..=height as f32).contains(&y), "{:?}", rotation);
+        }
+    }
+
+    #[test]
+    fn opposite_rotations_cancel_out() {
+        let (x, y) = Rotation::R90.point(10.0, 40.0, 100.0, 50.0);
+        // the turned screen is 50x100
+        assert_eq!(Rotation::R270.point(x, y, 50.0, 100.0), (10.0, 40.0));
+
+        let (x, y) = Rotation::R90.vector(3.0, -4.0);
+        assert_eq!(Rotation::R270.vector(x, y), (3.0, -4.0));
+    }
+
+    #[test]
+    fn vectors_turn_clockwise() {
+        // tilted towards the right edge ends up tilted towards the bottom
+        assert_eq!(Rotation::R90.vector(1.0, 0.0), (-0.0, 1.0));
+        assert_eq!(Rotation::R180.vector(1.0, 2.0), (-1.0, -2.0));
+        assert_eq!(Rotation::R270.vector(0.0, 1.0), (1.0, -0.0));
+    }
+
+    #[test]
+    fn angles_wrap_around() {
+        // same direction either way, it comes back as 180
+        assert_eq!(Rotation::R0.angle(-180.0), 180.0);
+        assert_eq!(Rotation::R90.angle(170.0), -100.0);
+        assert_eq!(Rotation::R180.angle(-90.0), 90.0);
+        assert_eq!(Rotation::R270.angle(0.0), -90.0);
+    }
+}
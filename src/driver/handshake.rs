@@ -0,0 +1,210 @@
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::{
+    data::{
+        ParseMode, ToucheData,
+        binary::BINARY_VERSION,
+        capabilities::Capabilities,
+        decoder::{ToucheDecoder, WireFormat},
+        rotation::Rotation,
+    },
+    error::Error,
+    source::ToucheSource,
+};
+
+use super::{OPCODE_CAPABILITIES, OPCODE_HANDSHAKE};
+
+/// Outcome of the handshake: the phone's screen and what both sides agreed on.
+pub(crate) struct Handshake {
+    pub(crate) width: i32,
+    pub(crate) height: i32,
+    pub(crate) capabilities: Capabilities,
+    pub(crate) format: WireFormat,
+    /// how the phone says it's held, upright unless it sent `R`
+    pub(crate) rotation: Rotation,
+}
+
+/// How long the rest of a handshake reply that came in pieces gets to show up.
+const REPLY_REST_TIMEOUT: Duration = Duration::from_millis(200);
+
+pub(crate) fn handshake(source: &mut dyn ToucheSource) -> Result<Handshake, Error> {
+    let reply = read_reply(source)?;
+    let (handshake, advertised) = negotiate(&reply)?;
+    // apps from before the handshake expect nothing but the opcode,
+    // only the ones that sent a `C` row get ours
+    if advertised {
+        let mut message = vec![OPCODE_CAPABILITIES];
+        message.extend_from_slice(&Capabilities::HOST.to_bytes());
+        source.send_opcode(&message)?;
+    }
+    Ok(handshake)
+}
+
+/// The rows of the phone's answer to the handshake. A long answer can come
+/// in more than one transfer, so this keeps reading while there's no screen
+/// size yet or a row is cut off. Rows from newer apps that we don't know
+/// are skipped, whatever else they sent still counts.
+fn read_reply(source: &mut dyn ToucheSource) -> Result<Vec<ToucheData>, Error> {
+    let mut decoder = ToucheDecoder::new(WireFormat::Text, ParseMode::Lenient);
+    let mut events = Vec::new();
+    let mut buffer = source.handshake(&[OPCODE_HANDSHAKE])?;
+    loop {
+        let frame = decoder.feed(&buffer)?;
+        for diagnostic in &frame.diagnostics {
+            debug!("skipping handshake row: {}", diagnostic);
+        }
+        events.extend(frame.events);
+
+        let has_size = events
+            .iter()
+            .any(|event| matches!(event, ToucheData::ScreenSize { .. }));
+        if has_size && !decoder.is_mid_record() {
+            return Ok(events);
+        }
+        match source.read_frame(REPLY_REST_TIMEOUT)? {
+            Some(rest) => buffer = rest,
+            None => break,
+        }
+    }
+    // nothing more is coming, so whatever is left is the last row,
+    // like from older apps that don't end the answer with a newline
+    events.extend(decoder.feed(b"\n")?.events);
+    Ok(events)
+}
+
+/// Makes sense of the phone's answer to the handshake. Also says whether
+/// the phone advertised its capabilities.
+fn negotiate(size_data: &[ToucheData]) -> Result<(Handshake, bool), Error> {
+    let (width, height) = size_data
+        .iter()
+        .find_map(|event| match event {
+            ToucheData::ScreenSize { x, y } => Some((*x, *y)),
+            _ => None,
+        })
+        .ok_or(Error::MissingScreenSize)?;
+
+    let advertised = size_data.iter().find_map(|event| match event {
+        ToucheData::Capabilities(capabilities) => Some(*capabilities),
+        _ => None,
+    });
+    let phone = advertised.unwrap_or_else(|| {
+        info!("phone didn't advertise capabilities, assuming a legacy app");
+        Capabilities::LEGACY
+    });
+    let capabilities = Capabilities::HOST.negotiate(&phone);
+    info!("phone capabilities: {:?}", phone);
+    info!("negotiated capabilities: {:?}", capabilities);
+
+    let format = if capabilities.protocol_version >= BINARY_VERSION {
+        WireFormat::Binary
+    } else {
+        WireFormat::Text
+    };
+    info!("negotiated wire format: {:?}", format);
+
+    let rotation = size_data
+        .iter()
+        .find_map(|event| match event {
+            ToucheData::Rotation(rotation) => Some(*rotation),
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    let handshake = Handshake {
+        width,
+        height,
+        capabilities,
+        format,
+        rotation,
+    };
+    Ok((handshake, advertised.is_some()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// A phone whose answer to the handshake comes in `pieces`.
+    struct Replies {
+        pieces: VecDeque<&'static [u8]>,
+        sent: Vec<Vec<u8>>,
+    }
+
+    impl Replies {
+        fn new(pieces: &[&'static [u8]]) -> Replies {
+            Replies {
+                pieces: pieces.iter().copied().collect(),
+                sent: Vec::new(),
+            }
+        }
+    }
+
+    impl ToucheSource for Replies {
+        fn handshake(&mut self, request: &[u8]) -> Result<Vec<u8>, Error> {
+            assert_eq!(request, [OPCODE_HANDSHAKE]);
+            Ok(self.pieces.pop_front().unwrap_or_default().to_vec())
+        }
+
+        fn read_frame(&mut self, _timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+            Ok(self.pieces.pop_front().map(<[u8]>::to_vec))
+        }
+
+        fn send_opcode(&mut self, opcode: &[u8]) -> Result<(), Error> {
+            self.sent.push(opcode.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn joins_an_answer_split_across_transfers() {
+        let mut phone = Replies::new(&[b"X\t1080\t24", b"00\nC\t1\t3", b"\t2\t60\n"]);
+
+        let handshake = handshake(&mut phone).unwrap();
+
+        assert_eq!((handshake.width, handshake.height), (1080, 2400));
+        assert_eq!(handshake.format, WireFormat::Binary);
+        assert_eq!(phone.sent[0][0], OPCODE_CAPABILITIES);
+        assert!(phone.pieces.is_empty());
+    }
+
+    #[test]
+    fn skips_rows_it_doesnt_know() {
+        let mut phone = Replies::new(&[b"X\t1080\t2400\nZ\tfrom the future\nC\t0\t3\t2\t60\n"]);
+
+        let handshake = handshake(&mut phone).unwrap();
+
+        assert_eq!((handshake.width, handshake.height), (1080, 2400));
+        assert_eq!(phone.sent.len(), 1);
+    }
+
+    #[test]
+    fn takes_an_answer_without_a_newline_from_a_legacy_app() {
+        let mut phone = Replies::new(&[b"X\t1080\t2400"]);
+
+        let handshake = handshake(&mut phone).unwrap();
+
+        assert_eq!((handshake.width, handshake.height), (1080, 2400));
+        assert_eq!(handshake.format, WireFormat::Text);
+        assert_eq!(
+            handshake.capabilities,
+            Capabilities::HOST.negotiate(&Capabilities::LEGACY)
+        );
+        // apps from before the handshake get nothing back
+        assert!(phone.sent.is_empty());
+    }
+
+    #[test]
+    fn fails_without_a_screen_size() {
+        let mut phone = Replies::new(&[b"C\t0\t3\t2\t60\n"]);
+
+        assert!(matches!(
+            handshake(&mut phone),
+            Err(Error::MissingScreenSize)
+        ));
+        assert!(phone.sent.is_empty());
+    }
+}
@@ -1,130 +1,498 @@
+mod handshake;
+mod orientation;
+pub(crate) mod replay;
+
 use core::result::Result;
 use std::time::Duration;
 
 use crate::{
-    aoa::AoaDevice,
-    data::{ToucheData, parse_touche_data},
-    graphics_tablet::GraphicsTabletDevice,
-    touchpad::TouchpadDevice,
+    config::live::LiveOptions,
+    data::{
+        ParseMode, ToucheData,
+        binary::BINARY_VERSION,
+        capabilities::Capabilities,
+        decoder::{ToucheDecoder, WireFormat},
+        rotation::Rotation,
+    },
+    error::Error,
+    graphics_tablet::TabletSettings,
+    sink::{InputSink, SinkKind, SinkSpec},
+    source::ToucheSource,
+    touchpad::TouchpadSettings,
 };
 
-use log::{error, info, trace};
+use handshake::{Handshake, handshake};
+use log::{error, info, trace, warn};
+use orientation::Orientation;
 
-// This function didn't hear about single responsibility principle
-pub(crate) fn driver_loop(aoa_device: AoaDevice) -> Result<(), ()> {
-    let opcode = vec![2];
-    match aoa_device.write(opcode) {
-        Ok(_) => {}
-        Err(e) => {
-            error!("opcode writing error! {}", e);
-            info!("error logs:\n{}", e);
-            return Err(());
+/// start streaming text rows
+pub(crate) const OPCODE_START_TEXT: u8 = 1;
+/// send `X`, `C` and `R` back
+pub(crate) const OPCODE_HANDSHAKE: u8 = 2;
+/// start streaming binary records, followed by the protocol version byte
+const OPCODE_START_BINARY: u8 = 3;
+/// the host capabilities, for phones that sent theirs
+pub(crate) const OPCODE_CAPABILITIES: u8 = 4;
+
+/// Knobs for a single driver session.
+#[derive(Debug, PartialEq)]
+pub(crate) struct DriverOptions {
+    /// how long the pen may stay silent before it's forced out of proximity
+    pub(crate) proximity_timeout: Duration,
+    /// overrides the rotation the phone reports, for apps stuck in portrait
+    pub(crate) rotation: Option<Rotation>,
+    /// recreate the virtual devices when the phone screen changes size,
+    /// instead of squeezing the new screen into the old axis ranges
+    pub(crate) rebuild_on_resize: bool,
+    pub(crate) tablet: TabletSettings,
+    pub(crate) touchpad: TouchpadSettings,
+    /// pause between the handshake and asking the phone to start streaming
+    pub(crate) stream_delay: Duration,
+    /// where input goes, each sink only gets created if the phone has input for it
+    pub(crate) sinks: Vec<SinkKind>,
+}
+
+impl Default for DriverOptions {
+    fn default() -> Self {
+        DriverOptions {
+            proximity_timeout: Duration::from_secs(2),
+            rotation: None,
+            rebuild_on_resize: true,
+            tablet: TabletSettings::default(),
+            touchpad: TouchpadSettings::default(),
+            stream_delay: Duration::from_millis(30),
+            sinks: vec![SinkKind::Tablet, SinkKind::Touchpad],
         }
     }
+}
 
-    let size_data_raw = match aoa_device.read() {
-        Ok(data) => data,
-        Err(e) => {
-            error!("size data retrieval error! maybe, disconnected? {}", e);
-            info!("error logs:\n{}", e);
-            return Err(());
+/// Sinks of one phone, along with what they were built for.
+/// They outlive a single connection, so a phone that drops off the bus for
+/// a moment comes back to the same devices.
+pub(crate) struct Devices {
+    sinks: Vec<Box<dyn InputSink>>,
+    width: i32,
+    height: i32,
+    capabilities: Capabilities,
+    shape: DeviceShape,
+}
+
+/// The options the virtual devices are built from. The rest of them,
+/// like the pressure curve, can change while the devices are alive.
+#[derive(PartialEq)]
+struct DeviceShape {
+    sinks: Vec<SinkKind>,
+    tablet_name: String,
+    tablet_ids: (u16, u16),
+    desktop: Option<(i32, i32)>,
+    pressure_resolution: i32,
+    distance_resolution: i32,
+    touchpad: TouchpadSettings,
+}
+
+impl DeviceShape {
+    fn of(options: &DriverOptions) -> DeviceShape {
+        DeviceShape {
+            sinks: options.sinks.clone(),
+            tablet_name: options.tablet.name.clone(),
+            tablet_ids: (options.tablet.vendor_id, options.tablet.product_id),
+            desktop: options.tablet.area.desktop,
+            pressure_resolution: options.tablet.pressure.resolution,
+            distance_resolution: options.tablet.distance_resolution,
+            touchpad: options.touchpad.clone(),
         }
-    };
+    }
+}
 
-    let size_data = match parse_touche_data(&size_data_raw) {
-        Ok(data) => data,
-        Err(e) => {
-            error!("data decoding error!");
-            info!("error logs:\n{}", e);
-            return Err(());
+impl Devices {
+    fn create(
+        width: i32,
+        height: i32,
+        capabilities: &Capabilities,
+        options: &DriverOptions,
+        label: &str,
+    ) -> Result<Devices, Error> {
+        let spec = SinkSpec {
+            label,
+            width,
+            height,
+            capabilities,
+            options,
+        };
+        let mut sinks = Vec::new();
+        for kind in &options.sinks {
+            sinks.extend(kind.create(&spec)?);
+        }
+        if sinks.is_empty() {
+            warn!("no sinks for {}, its input goes nowhere", label);
         }
-    };
 
-    if let Some(ToucheData::ScreenSize {
-        x: width,
-        y: height,
-    }) = size_data.first()
-    {
-        let mut touchetab = match GraphicsTabletDevice::new(*width, *height) {
-            Ok(tab) => tab,
-            Err(e) => {
-                error!("graphics tablet creation error! {}", e);
-                info!("error logs:\n{}", e);
-                return Err(());
-            }
+        Ok(Devices {
+            sinks,
+            width,
+            height,
+            capabilities: *capabilities,
+            shape: DeviceShape::of(options),
+        })
+    }
+
+    fn fits(
+        &self,
+        width: i32,
+        height: i32,
+        capabilities: &Capabilities,
+        options: &DriverOptions,
+    ) -> bool {
+        (self.width, self.height) == (width, height)
+            && self.capabilities == *capabilities
+            && self.shape == DeviceShape::of(options)
+    }
+
+    /// Replaces the devices with new ones, built for a new screen size or new options.
+    fn rebuild(
+        &mut self,
+        width: i32,
+        height: i32,
+        options: &DriverOptions,
+        label: &str,
+    ) -> Result<(), Error> {
+        // the old devices go first, so clients never see two of each
+        self.sinks.clear();
+        let capabilities = self.capabilities;
+        *self = Devices::create(width, height, &capabilities, options, label)?;
+        Ok(())
+    }
+
+    /// Applies options that don't need new devices.
+    fn update(&mut self, options: &DriverOptions, label: &str) {
+        let spec = SinkSpec {
+            label,
+            width: self.width,
+            height: self.height,
+            capabilities: &self.capabilities,
+            options,
         };
-        let mut touchepad = match TouchpadDevice::new(*width, *height) {
-            Ok(pad) => pad,
-            Err(e) => {
-                error!("touchpad creation error! {}", e);
-                info!("error logs:\n{}", e);
-                return Err(());
+        for sink in &mut self.sinks {
+            sink.update(&spec);
+        }
+    }
+
+    /// Hands a frame to every sink.
+    fn consume(&mut self, frame: &[ToucheData]) -> Result<(), Error> {
+        for sink in &mut self.sinks {
+            sink.consume(frame)?;
+        }
+        Ok(())
+    }
+
+    /// Lets sinks catch up with time passing, like a pen that went quiet.
+    fn flush(&mut self) {
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.flush() {
+                error!("{}", e);
+                info!("error logs:\n{:?}", e);
             }
-        };
+        }
+    }
 
-        std::thread::sleep(Duration::from_millis(30));
-
-        trace!("requesting data frame");
-        let opcode = vec![1];
-        let write_result = aoa_device.write(opcode);
-        match write_result {
-            Ok(_) => {}
-            Err(e) => {
-                error!("opcode writing error! {}", e);
-                info!("error logs:\n{}", e);
-                return Err(());
+    /// Lifts every finger and takes the pen out of proximity,
+    /// so nothing stays stuck down while the phone is away.
+    pub(crate) fn release(&mut self) {
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.shutdown() {
+                error!("{}", e);
+                info!("error logs:\n{:?}", e);
             }
         }
+    }
+}
+
+/// Runs a single connection to a phone, over whatever `source` it came on.
+///
+/// `parked` holds devices left over from an earlier connection of the same
+/// phone. They get reused if the phone still looks the same, and whatever
+/// devices this connection ends up with are released and put back there.
+///
+/// Returns `Ok` when the phone went away, `Err` when something else broke.
+pub(crate) fn driver_loop(
+    source: &mut dyn ToucheSource,
+    options: &mut LiveOptions,
+    label: &str,
+    parked: &mut Option<Devices>,
+) -> Result<(), Error> {
+    options.refresh();
+    let current = options.get();
+    let Handshake {
+        width,
+        height,
+        capabilities,
+        format,
+        rotation,
+    } = handshake(source)?;
+
+    let mut orientation = Orientation::new(
+        width,
+        height,
+        rotation,
+        current.rotation,
+        current.rebuild_on_resize,
+    );
+    info!(
+        "rotating input by {} degrees",
+        orientation.rotation().degrees()
+    );
+    let (width, height) = orientation.size();
 
-        loop {
-            let res = aoa_device.read();
-
-            trace!("received. parsing data frame...");
-            match res {
-                Ok(res) => {
-                    let events = parse_touche_data(&res);
-                    let events = match events {
-                        Ok(events) => events,
-                        Err(e) => {
-                            error!("data decoding error!");
-                            info!("error logs:\n{}", e);
-                            return Err(());
-                        }
-                    };
-
-                    // new approach
-                    match touchepad.emit(&events[..]) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("touchpad event processing error!");
-                            info!("error logs:\n{}", e);
-                            return Err(());
-                        }
-                    }
-
-                    // graphics tablet events emission
-                    match touchetab.emit(&events[..]) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("graphics tablet event processing error!");
-                            info!("error logs:\n{}", e);
-                        }
-                    }
-
-                    trace!("finished parsing data frame");
-                    std::thread::sleep(Duration::from_millis(5));
-                }
-                Err(e) => {
-                    error!("TRANSFER ERROR! perhaps, device disconnected?");
-                    info!("error logs:\n{}", e);
-                    break;
-                }
+    let mut devices = match parked.take() {
+        Some(devices) if devices.fits(width, height, &capabilities, &current) => {
+            info!("picking up parked devices");
+            devices
+        }
+        stale => {
+            drop(stale);
+            Devices::create(width, height, &capabilities, &current, label)?
+        }
+    };
+
+    let result = stream(
+        source,
+        &mut devices,
+        &mut orientation,
+        format,
+        options,
+        label,
+    );
+    devices.release();
+    *parked = Some(devices);
+    result
+}
+
+// This function didn't hear about single responsibility principle
+fn stream(
+    source: &mut dyn ToucheSource,
+    devices: &mut Devices,
+    orientation: &mut Orientation,
+    format: WireFormat,
+    options: &mut LiveOptions,
+    label: &str,
+) -> Result<(), Error> {
+    let mut current = options.get();
+
+    std::thread::sleep(current.stream_delay);
+    trace!("requesting data frame");
+    let opcode = match format {
+        WireFormat::Text => &[OPCODE_START_TEXT][..],
+        WireFormat::Binary => &[OPCODE_START_BINARY, BINARY_VERSION],
+    };
+    source.send_opcode(opcode)?;
+
+    let mut decoder = ToucheDecoder::new(format, ParseMode::Lenient);
+    loop {
+        if options.refresh() {
+            current = options.get();
+            reconfigure(devices, orientation, &current, label)?;
+        }
+
+        let res = source.read_frame(current.proximity_timeout);
+
+        trace!("received. parsing data frame...");
+        match res {
+            Ok(None) => {
+                trace!("no data within {:?}", current.proximity_timeout);
+            }
+            Ok(Some(res)) => {
+                process(&res, &mut decoder, devices, orientation, &current, label)?;
+                trace!("finished parsing data frame");
+            }
+            Err(e) if e.is_disconnect() => {
+                info!("{}", e);
+                return Ok(());
             }
+            Err(e) => return Err(e),
         }
+        // only once the frame is in, so a pen that just came back isn't timed out first
+        devices.flush();
+    }
+}
+
+/// Decodes one buffer from the phone and feeds it to the devices.
+fn process(
+    buffer: &[u8],
+    decoder: &mut ToucheDecoder,
+    devices: &mut Devices,
+    orientation: &mut Orientation,
+    options: &DriverOptions,
+    label: &str,
+) -> Result<(), Error> {
+    let frame = decoder.feed(buffer)?;
+    for diagnostic in &frame.diagnostics {
+        warn!("malformed data row: {}", diagnostic);
+    }
+    let mut events = frame.events;
+
+    if orientation.apply(&mut events) {
+        let (width, height) = orientation.size();
+        info!(
+            "phone screen is now {}x{}, rebuilding devices",
+            width, height
+        );
+        devices.rebuild(width, height, options, label)?;
+    }
+
+    devices.consume(&events)
+}
+
+/// Applies a reloaded config to a running session. The devices only get
+/// rebuilt when their axes or identity changed, everything else is swapped
+/// between two frames.
+fn reconfigure(
+    devices: &mut Devices,
+    orientation: &mut Orientation,
+    options: &DriverOptions,
+    label: &str,
+) -> Result<(), Error> {
+    info!("config of {} changed, applying it", label);
+    let resized = orientation.reconfigure(options.rotation, options.rebuild_on_resize);
+    let (width, height) = orientation.size();
+    let capabilities = devices.capabilities;
+    if resized || !devices.fits(width, height, &capabilities, options) {
+        info!("device settings changed, rebuilding devices");
+        devices.release();
+        devices.rebuild(width, height, options, label)?;
     } else {
-        error!("wrong size data!");
-        info!("error logs:\nWrong size data received from device");
-        return Result::Err(());
+        devices.update(options, label);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, io, sync::Arc};
+
+    use super::*;
+    use crate::config::{
+        Config,
+        live::{LiveConfig, LiveOptions},
+    };
+
+    /// A phone that answers the handshake with `reply`, then sends `frames`
+    /// and hangs up.
+    struct MockPhone {
+        reply: &'static [u8],
+        frames: VecDeque<Result<Option<Vec<u8>>, Error>>,
+        /// everything the driver sent after the handshake request
+        sent: Vec<Vec<u8>>,
+    }
+
+    impl MockPhone {
+        fn new(reply: &'static [u8], frames: &[&[u8]]) -> MockPhone {
+            MockPhone {
+                reply,
+                frames: frames
+                    .iter()
+                    .map(|frame| Ok(Some(frame.to_vec())))
+                    .collect(),
+                sent: Vec::new(),
+            }
+        }
+    }
+
+    impl ToucheSource for MockPhone {
+        fn handshake(&mut self, request: &[u8]) -> Result<Vec<u8>, Error> {
+            assert_eq!(request, [OPCODE_HANDSHAKE]);
+            Ok(self.reply.to_vec())
+        }
+
+        fn read_frame(&mut self, _timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+            self.frames.pop_front().unwrap_or(Err(Error::Closed))
+        }
+
+        fn send_opcode(&mut self, opcode: &[u8]) -> Result<(), Error> {
+            self.sent.push(opcode.to_vec());
+            Ok(())
+        }
+    }
+
+    /// No sinks, so nothing needs uinput, and no waiting to start streaming.
+    fn options() -> LiveOptions {
+        let mut config = Config::default();
+        config.defaults.sinks = Some(Vec::new());
+        config.defaults.stream_delay_ms = Some(0);
+        LiveOptions::new(Arc::new(LiveConfig::new(config)), "mock")
+    }
+
+    fn drive(phone: &mut MockPhone, parked: &mut Option<Devices>) -> Result<(), Error> {
+        driver_loop(phone, &mut options(), "mock", parked)
+    }
+
+    #[test]
+    fn streams_text_from_a_legacy_phone_until_it_hangs_up() {
+        let mut phone = MockPhone::new(
+            b"X\t1080\t2400\n",
+            &[b"S\t100\t200\t1\t0.5\nF\t1", b"0\t20\t1\t0\n"],
+        );
+        // a phone going quiet for a bit is nothing to hang up over
+        phone.frames.insert(1, Ok(None));
+        let mut parked = None;
+
+        drive(&mut phone, &mut parked).unwrap();
+
+        // no `C` row, so no capabilities back
+        assert_eq!(phone.sent, [[OPCODE_START_TEXT]]);
+        assert!(phone.frames.is_empty());
+        assert!(parked.is_some());
+    }
+
+    #[test]
+    fn sends_capabilities_to_a_phone_that_advertised_its_own() {
+        let mut phone = MockPhone::new(b"X\t1080\t2400\nC\t0\t3\t2\t60\n", &[]);
+        let mut parked = None;
+
+        drive(&mut phone, &mut parked).unwrap();
+
+        let mut capabilities = vec![OPCODE_CAPABILITIES];
+        capabilities.extend_from_slice(&Capabilities::HOST.to_bytes());
+        assert_eq!(phone.sent, [capabilities, vec![OPCODE_START_TEXT]]);
+    }
+
+    #[test]
+    fn asks_a_phone_that_speaks_binary_for_binary() {
+        let mut phone = MockPhone::new(b"X\t1080\t2400\nC\t1\t3\t2\t60\n", &[]);
+        let mut parked = None;
+
+        drive(&mut phone, &mut parked).unwrap();
+
+        assert_eq!(
+            phone.sent.last().unwrap(),
+            &[OPCODE_START_BINARY, BINARY_VERSION]
+        );
+    }
+
+    #[test]
+    fn parks_the_devices_when_the_phone_fails() {
+        let mut phone = MockPhone::new(b"X\t1080\t2400\n", &[]);
+        phone
+            .frames
+            .push_back(Err(Error::Network(io::Error::other("boom"))));
+        let mut parked = None;
+
+        let result = drive(&mut phone, &mut parked);
+
+        assert!(matches!(result, Err(Error::Network(_))));
+        assert!(parked.is_some());
+    }
+
+    #[test]
+    fn fails_without_a_screen_size() {
+        let mut phone = MockPhone::new(b"C\t0\t3\t2\t60\n", &[]);
+        // the rest of the answer never comes
+        phone.frames.push_back(Ok(None));
+        let mut parked = None;
+
+        let result = drive(&mut phone, &mut parked);
+
+        assert!(matches!(result, Err(Error::MissingScreenSize)));
+        assert!(phone.sent.is_empty());
+        assert!(parked.is_none());
     }
-    Result::Err(())
 }
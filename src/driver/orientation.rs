@@ -0,0 +1,249 @@
+use log::info;
+
+use crate::data::{ToucheData, rotation::Rotation};
+
+/// Keeps track of how the phone is turned and rewrites coordinates so they
+/// come out upright on the device axes.
+///
+/// When the upright screen changes size - the phone got turned, or a foldable
+/// got unfolded - the axes either follow it, and the caller rebuilds its
+/// devices, or stay put and new coordinates get scaled onto them.
+pub(crate) struct Orientation {
+    /// phone screen as reported, before any rotation
+    width: i32,
+    height: i32,
+    reported: Rotation,
+    /// set by the user, wins over whatever the phone reports
+    manual: Option<Rotation>,
+    /// axes the virtual devices were created with
+    axes: (i32, i32),
+    follow_size: bool,
+}
+
+impl Orientation {
+    pub(crate) fn new(
+        width: i32,
+        height: i32,
+        reported: Rotation,
+        manual: Option<Rotation>,
+        follow_size: bool,
+    ) -> Orientation {
+        let rotation = manual.unwrap_or(reported);
+        Orientation {
+            width,
+            height,
+            reported,
+            manual,
+            axes: rotation.size(width, height),
+            follow_size,
+        }
+    }
+
+    pub(crate) fn rotation(&self) -> Rotation {
+        self.manual.unwrap_or(self.reported)
+    }
+
+    /// Upright screen size, the one to create devices with.
+    pub(crate) fn size(&self) -> (i32, i32) {
+        self.axes
+    }
+
+    /// Takes new settings from a reloaded config. Returns `true` when the
+    /// axes changed and the devices have to be rebuilt.
+    pub(crate) fn reconfigure(&mut self, manual: Option<Rotation>, follow_size: bool) -> bool {
+        self.manual = manual;
+        self.follow_size = follow_size;
+        self.follow()
+    }
+
+    /// Rewrites `events` in place. Returns `true` when the axes changed
+    /// and the devices have to be rebuilt with [`Orientation::size`].
+    pub(crate) fn apply(&mut self, events: &mut [ToucheData]) -> bool {
+        let mut resized = false;
+        for event in events {
+            match event {
+                ToucheData::ScreenSize { x, y } => {
+                    if (*x, *y) != (self.width, self.height) {
+                        info!("phone screen changed to {}x{}", x, y);
+                    }
+                    self.width = *x;
+                    self.height = *y;
+                    resized |= self.follow();
+                }
+                ToucheData::Rotation(rotation) => {
+                    if *rotation != self.reported {
+                        info!(
+                            "phone turned to {} degrees{}",
+                            rotation.degrees(),
+                            if self.manual.is_some() {
+                                ", ignored in favour of the manual rotation"
+                            } else {
+                                ""
+                            }
+                        );
+                    }
+                    self.reported = *rotation;
+                    resized |= self.follow();
+                }
+                ToucheData::StylusFrame {
+                    x,
+                    y,
+                    tilt_x,
+                    tilt_y,
+                    orientation,
+                    ..
+                } => {
+                    (*x, *y) = self.point(*x, *y);
+                    let rotation = self.rotation();
+                    if tilt_x.is_some() || tilt_y.is_some() {
+                        let (rotated_x, rotated_y) =
+                            rotation.vector(tilt_x.unwrap_or(0.0), tilt_y.unwrap_or(0.0));
+                        *tilt_x = Some(rotated_x);
+                        *tilt_y = Some(rotated_y);
+                    }
+                    if let Some(orientation) = orientation {
+                        *orientation = rotation.angle(*orientation);
+                    }
+                }
+                ToucheData::TouchFrame { x, y, .. } => {
+                    (*x, *y) = self.point(*x, *y);
+                }
+                ToucheData::Capabilities(_) => {}
+            }
+        }
+        resized
+    }
+
+    fn follow(&mut self) -> bool {
+        let size = self.rotation().size(self.width, self.height);
+        if self.follow_size && size != self.axes {
+            self.axes = size;
+            return true;
+        }
+        false
+    }
+
+    /// Rotates a point upright and scales it onto the device axes,
+    /// in case the screen has changed since they were created.
+    fn point(&self, x: f32, y: f32) -> (f32, f32) {
+        let rotation = self.rotation();
+        let (x, y) = rotation.point(x, y, self.width as f32, self.height as f32);
+        let (width, height) = rotation.size(self.width, self.height);
+        let scale = |value: f32, axis: i32, len: i32| {
+            if len > 0 {
+                value * axis as f32 / len as f32
+            } else {
+                value
+            }
+        };
+        (scale(x, self.axes.0, width), scale(y, self.axes.1, height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::StylusTool;
+
+    fn touch(x: f32, y: f32) -> ToucheData {
+        ToucheData::TouchFrame {
+            x,
+            y,
+            touch_id: 0,
+            pressed: true,
+        }
+    }
+
+    fn touch_at(event: &ToucheData) -> (f32, f32) {
+        match event {
+            ToucheData::TouchFrame { x, y, .. } => (*x, *y),
+            _ => panic!("not a touch"),
+        }
+    }
+
+    #[test]
+    fn turning_the_phone_rebuilds_the_axes() {
+        let mut orientation = Orientation::new(100, 50, Rotation::R0, None, true);
+        let mut events = [ToucheData::Rotation(Rotation::R90), touch(10.0, 40.0)];
+
+        assert!(orientation.apply(&mut events));
+        assert_eq!(orientation.size(), (50, 100));
+        assert_eq!(touch_at(&events[1]), (10.0, 10.0));
+    }
+
+    #[test]
+    fn fixed_axes_get_the_rotated_points_scaled_onto_them() {
+        let mut orientation = Orientation::new(100, 50, Rotation::R0, None, false);
+        let mut events = [ToucheData::Rotation(Rotation::R90), touch(10.0, 40.0)];
+
+        assert!(!orientation.apply(&mut events));
+        assert_eq!(orientation.size(), (100, 50));
+        // (10, 10) on the turned 50x100 screen
+        assert_eq!(touch_at(&events[1]), (20.0, 5.0));
+    }
+
+    #[test]
+    fn fixed_axes_survive_an_unfolded_screen() {
+        let mut orientation = Orientation::new(100, 50, Rotation::R0, None, false);
+        let mut events = [
+            ToucheData::ScreenSize { x: 200, y: 100 },
+            touch(200.0, 100.0),
+        ];
+
+        assert!(!orientation.apply(&mut events));
+        assert_eq!(touch_at(&events[1]), (100.0, 50.0));
+    }
+
+    #[test]
+    fn manual_rotation_wins_over_the_phone() {
+        let mut orientation = Orientation::new(100, 50, Rotation::R0, Some(Rotation::R90), true);
+        let mut events = [ToucheData::Rotation(Rotation::R180), touch(10.0, 40.0)];
+
+        assert_eq!(orientation.size(), (50, 100));
+        assert!(!orientation.apply(&mut events));
+        assert_eq!(touch_at(&events[1]), (10.0, 10.0));
+    }
+
+    #[test]
+    fn reconfigure_reports_when_the_axes_change() {
+        let mut orientation = Orientation::new(100, 50, Rotation::R0, None, true);
+
+        assert!(orientation.reconfigure(Some(Rotation::R270), true));
+        assert_eq!(orientation.size(), (50, 100));
+        // same size, different way up
+        assert!(!orientation.reconfigure(Some(Rotation::R90), true));
+    }
+
+    #[test]
+    fn pen_tilt_and_orientation_turn_with_the_screen() {
+        let mut orientation = Orientation::new(100, 50, Rotation::R90, None, true);
+        let mut events = [ToucheData::StylusFrame {
+            x: 10.0,
+            y: 40.0,
+            pressed: true,
+            pressure: None,
+            tilt_x: Some(30.0),
+            tilt_y: None,
+            orientation: Some(170.0),
+            distance: None,
+            tool: StylusTool::Pen,
+            primary_button: false,
+            secondary_button: false,
+            in_proximity: true,
+        }];
+
+        orientation.apply(&mut events);
+
+        assert!(matches!(
+            events[0],
+            ToucheData::StylusFrame {
+                x: 10.0,
+                y: 10.0,
+                tilt_x: Some(0.0),
+                tilt_y: Some(30.0),
+                orientation: Some(-100.0),
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use crate::{
+    config::{
+        Config,
+        live::{LiveConfig, LiveOptions},
+    },
+    error::Error,
+    recording::Recording,
+    source::ToucheSource,
+};
+
+use super::driver_loop;
+
+/// Label the replayed devices get in place of a serial number,
+/// and the config profile a replay uses.
+const REPLAY_LABEL: &str = "replay";
+
+/// Plays a recording back through fresh virtual devices, the same way the
+/// phone's data went through them when it was recorded.
+///
+/// `speed` scales time, 2.0 plays twice as fast and 0.0 doesn't wait at all.
+pub(crate) fn replay(path: &Path, config: Config, speed: f32) -> Result<(), Error> {
+    let mut source = ReplaySource {
+        recording: Recording::open(path)?,
+        speed,
+        pending: None,
+        first: Duration::ZERO,
+        start: Instant::now(),
+    };
+    let mut options = LiveOptions::new(Arc::new(LiveConfig::new(config)), REPLAY_LABEL);
+    // nothing comes back to pick the devices up, they go once the replay is over
+    let mut devices = None;
+
+    info!("replaying {}", path.display());
+    driver_loop(&mut source, &mut options, REPLAY_LABEL, &mut devices)?;
+    info!("replay finished");
+    Ok(())
+}
+
+/// A recording posing as a phone. Buffers come out as far apart as they
+/// went in, scaled by `speed`, and the end of the file is a disconnect.
+struct ReplaySource {
+    recording: Recording,
+    speed: f32,
+    /// the next buffer, when it isn't due yet
+    pending: Option<(Duration, Vec<u8>)>,
+    /// when the handshake was recorded, everything after it is timed from there
+    first: Duration,
+    start: Instant,
+}
+
+impl ReplaySource {
+    fn due(&self, at: Duration) -> Instant {
+        if self.speed > 0.0 {
+            self.start + at.saturating_sub(self.first).div_f32(self.speed)
+        } else {
+            Instant::now()
+        }
+    }
+}
+
+impl ToucheSource for ReplaySource {
+    fn handshake(&mut self, _request: &[u8]) -> Result<Vec<u8>, Error> {
+        let Some((at, reply)) = self.recording.next_buffer()? else {
+            return Err(Error::Recording {
+                path: self.recording.path().to_owned(),
+                source: io::Error::new(ErrorKind::InvalidData, "the recording is empty"),
+            });
+        };
+        self.first = at;
+        self.start = Instant::now();
+        Ok(reply)
+    }
+
+    fn read_frame(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+        if self.pending.is_none() {
+            self.pending = self.recording.next_buffer()?;
+        }
+        let Some((at, _)) = &self.pending else {
+            return Err(Error::Closed);
+        };
+
+        // a long pause in the recording times out like a quiet phone would
+        let due = self.due(*at);
+        let now = Instant::now();
+        if due > now + timeout {
+            thread::sleep(timeout);
+            return Ok(None);
+        }
+        thread::sleep(due.saturating_duration_since(now));
+        Ok(self.pending.take().map(|(_, buffer)| buffer))
+    }
+
+    fn send_opcode(&mut self, _opcode: &[u8]) -> Result<(), Error> {
+        // the phone got the real thing while recording
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use std::{fmt, io, net::SocketAddr, path::PathBuf};
+
+use nusb::transfer::TransferError;
+
+use crate::data::ParseError;
+
+/// Everything that can stop the driver from talking to a phone,
+/// or from starting at all.
+///
+/// The `Display` messages are meant for people, not for us: each one says
+/// what went wrong and, where there is one, the usual fix.
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// opening the USB device failed
+    UsbOpen(io::Error),
+    /// claiming the accessory interface failed
+    UsbClaim(io::Error),
+    /// the accessory interface lacks a bulk endpoint in this direction
+    NoEndpoint { direction: &'static str },
+    /// the phone doesn't speak Android Open Accessory, or not a version we need
+    AoaUnsupported { version: Option<u8> },
+    /// one of the control requests that switch a phone to accessory mode failed
+    AoaHandshake(TransferError),
+    /// a bulk transfer failed mid-session
+    Transfer(TransferError),
+    /// the phone hung up, on a transport that isn't USB
+    Closed,
+    /// the phone answered the handshake without an `X` row
+    MissingScreenSize,
+    /// the phone sent something we couldn't parse
+    Decode(ParseError),
+    /// creating a virtual device failed
+    Uinput {
+        device: &'static str,
+        source: io::Error,
+    },
+    /// writing events to a virtual device failed
+    Emit {
+        device: &'static str,
+        source: io::Error,
+    },
+    /// the config file exists but can't be read
+    ConfigRead { path: PathBuf, source: io::Error },
+    /// the config file isn't valid TOML, or has settings we don't know
+    ConfigParse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    /// a setting in the config parses, but makes no sense
+    Config {
+        path: PathBuf,
+        key: String,
+        reason: &'static str,
+    },
+    /// a session recording can't be written or read back
+    Recording { path: PathBuf, source: io::Error },
+    /// the address to take phones over the network on is taken or invalid
+    Listen { addr: SocketAddr, source: io::Error },
+    /// a network connection to a phone broke, other than by hanging up
+    Network(io::Error),
+    /// a phone on the network didn't get through pairing, or we didn't
+    Pairing(String),
+    /// the keys that secure the network connection can't be read or saved
+    Keys { path: PathBuf, source: io::Error },
+    /// running adb to reach a phone failed
+    Adb(io::Error),
+}
+
+impl Error {
+    /// Whether the phone is simply gone, as opposed to misbehaving.
+    pub(crate) fn is_disconnect(&self) -> bool {
+        matches!(
+            self,
+            Error::Transfer(TransferError::Disconnected) | Error::Closed
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UsbOpen(e) if e.kind() == io::ErrorKind::PermissionDenied => write!(
+                f,
+                "permission denied opening the USB device. \
+                 Install udev rules for your phone (see `touche-driver udev`) or run as root"
+            ),
+            Error::UsbOpen(e) => write!(f, "failed to open the USB device: {}", e),
+            Error::UsbClaim(e) => write!(
+                f,
+                "failed to claim the accessory interface: {}. \
+                 Another program, such as an MTP client, may be holding it",
+                e
+            ),
+            Error::NoEndpoint { direction } => write!(
+                f,
+                "the accessory interface has no bulk {} endpoint. \
+                 Make sure the touche app is open on the phone and replug it",
+                direction
+            ),
+            Error::AoaUnsupported {
+                version: Some(version),
+            } => write!(
+                f,
+                "the phone reports Android Open Accessory version {}, \
+                 but versions 1 and 2 are supported",
+                version
+            ),
+            Error::AoaUnsupported { version: None } => write!(
+                f,
+                "the phone doesn't support Android Open Accessory. \
+                 Unlock it, enable USB debugging and replug it"
+            ),
+            Error::AoaHandshake(e) => write!(
+                f,
+                "switching the phone to accessory mode failed: {}. Unplug it and plug it back in",
+                e
+            ),
+            Error::Transfer(TransferError::Disconnected) => {
+                write!(f, "the phone was disconnected")
+            }
+            Error::Transfer(e) => write!(
+                f,
+                "USB transfer failed: {}. Try another cable or USB port",
+                e
+            ),
+            Error::Closed => write!(f, "the phone closed the connection"),
+            Error::MissingScreenSize => write!(
+                f,
+                "the phone never sent its screen size. Update the touche app on the phone"
+            ),
+            Error::Decode(e) => write!(f, "couldn't decode data from the phone: {}", e),
+            Error::Uinput { device, source } => match source.kind() {
+                io::ErrorKind::PermissionDenied => write!(
+                    f,
+                    "can't create the virtual {}: no access to /dev/uinput. \
+                     Add yourself to the group owning it (usually `input`) or run as root",
+                    device
+                ),
+                io::ErrorKind::NotFound => write!(
+                    f,
+                    "can't create the virtual {}: /dev/uinput is missing. \
+                     Load the module with `modprobe uinput`",
+                    device
+                ),
+                _ => write!(f, "can't create the virtual {}: {}", device, source),
+            },
+            Error::Emit { device, source } => {
+                write!(
+                    f,
+                    "writing events to the virtual {} failed: {}",
+                    device, source
+                )
+            }
+            Error::ConfigRead { path, source } => {
+                write!(f, "can't read the config at {}: {}", path.display(), source)
+            }
+            Error::ConfigParse { path, source } => write!(
+                f,
+                "the config at {} is broken. \
+                 Fix it or move it away to start with defaults:\n{}",
+                path.display(),
+                source
+            ),
+            Error::Config { path, key, reason } => write!(
+                f,
+                "`{}` in the config at {} {}",
+                key,
+                path.display(),
+                reason
+            ),
+            Error::Recording { path, source } => {
+                write!(
+                    f,
+                    "can't use the recording at {}: {}",
+                    path.display(),
+                    source
+                )
+            }
+            Error::Listen { addr, source } if source.kind() == io::ErrorKind::AddrInUse => write!(
+                f,
+                "{} is already in use. Is another touche-driver running?",
+                addr
+            ),
+            Error::Listen { addr, source } => write!(f, "can't listen on {}: {}", addr, source),
+            Error::Network(e) => write!(f, "network connection failed: {}", e),
+            Error::Pairing(reason) => write!(f, "pairing failed: {}", reason),
+            Error::Keys { path, source } => {
+                write!(f, "can't use the keys at {}: {}", path.display(), source)
+            }
+            Error::Adb(e) if e.kind() == io::ErrorKind::NotFound => write!(
+                f,
+                "adb isn't installed. Install the Android platform tools, \
+                 or set `adb.path` in the config"
+            ),
+            Error::Adb(e) => write!(
+                f,
+                "adb failed: {}. Make sure the phone trusts this computer for USB debugging",
+                e
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::UsbOpen(e)
+            | Error::UsbClaim(e)
+            | Error::Uinput { source: e, .. }
+            | Error::Emit { source: e, .. }
+            | Error::ConfigRead { source: e, .. }
+            | Error::Recording { source: e, .. }
+            | Error::Keys { source: e, .. }
+            | Error::Listen { source: e, .. }
+            | Error::Network(e)
+            | Error::Adb(e) => Some(e),
+            Error::ConfigParse { source, .. } => Some(source),
+            Error::AoaHandshake(e) | Error::Transfer(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            Error::NoEndpoint { .. }
+            | Error::AoaUnsupported { .. }
+            | Error::Closed
+            | Error::MissingScreenSize
+            | Error::Config { .. }
+            | Error::Pairing(_) => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Decode(e)
+    }
+}
@@ -0,0 +1,202 @@
+use serde::Deserialize;
+
+/// Axis-aligned rectangle, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub(crate) struct Rect {
+    pub(crate) x: f32,
+    pub(crate) y: f32,
+    pub(crate) width: f32,
+    pub(crate) height: f32,
+}
+
+impl Rect {
+    pub(crate) fn has_area(&self) -> bool {
+        self.width > 0.0 && self.height > 0.0
+    }
+}
+
+/// Which part of the phone drives which part of the desktop.
+///
+/// The tablet axes span the whole `desktop`, and the active `input` area of
+/// the phone lands on the `output` region of it. With everything left at
+/// `None` the phone screen maps 1:1 onto the axes, just like before this
+/// existed, and the compositor decides where that goes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct AreaMapping {
+    /// active part of the phone screen, in phone pixels; the whole screen if `None`
+    pub(crate) input: Option<Rect>,
+    /// size of the virtual desktop the tablet axes span; the phone screen size if `None`
+    pub(crate) desktop: Option<(i32, i32)>,
+    /// region of the desktop the input area lands on; the whole desktop if `None`
+    pub(crate) output: Option<Rect>,
+    /// trim the input area so strokes keep their shape, instead of stretching
+    /// it over an output region of a different aspect ratio
+    pub(crate) preserve_aspect: bool,
+}
+
+/// [`AreaMapping`] resolved against a particular phone screen.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AreaTransform {
+    input: Rect,
+    output: Rect,
+    /// axis maximums to register on the device
+    pub(crate) axis_max: (i32, i32),
+}
+
+impl AreaTransform {
+    pub(crate) fn new(mapping: &AreaMapping, width: i32, height: i32) -> AreaTransform {
+        let (desktop_width, desktop_height) = mapping.desktop.unwrap_or((width, height));
+        let mut input = mapping.input.unwrap_or(Rect {
+            x: 0.0,
+            y: 0.0,
+            width: width as f32,
+            height: height as f32,
+        });
+        let output = mapping.output.unwrap_or(Rect {
+            x: 0.0,
+            y: 0.0,
+            width: desktop_width as f32,
+            height: desktop_height as f32,
+        });
+
+        if mapping.preserve_aspect && input.height > 0.0 && output.height > 0.0 {
+            let output_ratio = output.width / output.height;
+            if input.width / input.height > output_ratio {
+                let trimmed = input.height * output_ratio;
+                input.x += (input.width - trimmed) / 2.0;
+                input.width = trimmed;
+            } else {
+                let trimmed = input.width / output_ratio;
+                input.y += (input.height - trimmed) / 2.0;
+                input.height = trimmed;
+            }
+        }
+
+        AreaTransform {
+            input,
+            output,
+            axis_max: (desktop_width, desktop_height),
+        }
+    }
+
+    /// Maps a point on the phone to axis coordinates.
+    /// Points outside the active area stick to its nearest edge.
+    pub(crate) fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        let along = |value: f32, start: f32, len: f32| {
+            if len > 0.0 {
+                ((value - start) / len).clamp(0.0, 1.0)
+            } else {
+                0.0
+            }
+        };
+        let u = along(x, self.input.x, self.input.width);
+        let v = along(y, self.input.y, self.input.height);
+        (
+            self.output.x + u * self.output.width,
+            self.output.y + v * self.output.height,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: f32, y: f32, width: f32, height: f32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn maps_the_screen_one_to_one_by_default() {
+        let transform = AreaTransform::new(&AreaMapping::default(), 1080, 2400);
+
+        assert_eq!(transform.axis_max, (1080, 2400));
+        assert_eq!(transform.apply(0.0, 0.0), (0.0, 0.0));
+        assert_eq!(transform.apply(540.0, 1200.0), (540.0, 1200.0));
+    }
+
+    #[test]
+    fn lands_on_the_output_region_of_the_desktop() {
+        let mapping = AreaMapping {
+            desktop: Some((3840, 2160)),
+            output: Some(rect(1920.0, 0.0, 1920.0, 1080.0)),
+            ..AreaMapping::default()
+        };
+        let transform = AreaTransform::new(&mapping, 1000, 500);
+
+        assert_eq!(transform.axis_max, (3840, 2160));
+        assert_eq!(transform.apply(0.0, 0.0), (1920.0, 0.0));
+        assert_eq!(transform.apply(500.0, 250.0), (2880.0, 540.0));
+        assert_eq!(transform.apply(1000.0, 500.0), (3840.0, 1080.0));
+    }
+
+    #[test]
+    fn sticks_points_outside_the_input_area_to_its_edge() {
+        let mapping = AreaMapping {
+            input: Some(rect(100.0, 100.0, 200.0, 200.0)),
+            ..AreaMapping::default()
+        };
+        let transform = AreaTransform::new(&mapping, 400, 400);
+
+        assert_eq!(transform.apply(0.0, 0.0), (0.0, 0.0));
+        assert_eq!(transform.apply(200.0, 200.0), (200.0, 200.0));
+        assert_eq!(transform.apply(999.0, 350.0), (400.0, 400.0));
+    }
+
+    #[test]
+    fn preserve_aspect_trims_a_wide_input_on_the_sides() {
+        let mapping = AreaMapping {
+            desktop: Some((1000, 1000)),
+            preserve_aspect: true,
+            ..AreaMapping::default()
+        };
+        // 2:1 onto 1:1 keeps the middle 500x500
+        let transform = AreaTransform::new(&mapping, 1000, 500);
+
+        assert_eq!(transform.apply(250.0, 0.0), (0.0, 0.0));
+        assert_eq!(transform.apply(500.0, 250.0), (500.0, 500.0));
+        assert_eq!(transform.apply(750.0, 500.0), (1000.0, 1000.0));
+        assert_eq!(transform.apply(0.0, 0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn preserve_aspect_trims_a_tall_input_at_the_top_and_bottom() {
+        let mapping = AreaMapping {
+            desktop: Some((1600, 900)),
+            preserve_aspect: true,
+            ..AreaMapping::default()
+        };
+        // a 1080x2400 portrait screen onto 16:9 keeps a 1080x607.5 band
+        let transform = AreaTransform::new(&mapping, 1080, 2400);
+
+        let (_, top) = transform.apply(0.0, 896.25);
+        let (_, bottom) = transform.apply(0.0, 1503.75);
+        assert!(top.abs() < 0.01);
+        assert!((bottom - 900.0).abs() < 0.01);
+        assert_eq!(transform.apply(540.0, 1200.0), (800.0, 450.0));
+    }
+
+    #[test]
+    fn stretches_without_preserve_aspect() {
+        let mapping = AreaMapping {
+            desktop: Some((1000, 1000)),
+            ..AreaMapping::default()
+        };
+        let transform = AreaTransform::new(&mapping, 1000, 500);
+
+        assert_eq!(transform.apply(0.0, 0.0), (0.0, 0.0));
+        assert_eq!(transform.apply(1000.0, 500.0), (1000.0, 1000.0));
+    }
+
+    #[test]
+    fn has_area_needs_both_sides() {
+        assert!(rect(0.0, 0.0, 1.0, 1.0).has_area());
+        assert!(!rect(0.0, 0.0, 0.0, 1.0).has_area());
+        assert!(!rect(0.0, 0.0, 1.0, -1.0).has_area());
+    }
+}
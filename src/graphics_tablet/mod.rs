@@ -1,54 +1,211 @@
-use std::io;
+pub(crate) mod area;
+pub(crate) mod pressure;
 
-use crate::data::ToucheData;
+#[cfg(target_os = "linux")]
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+#[cfg(target_os = "linux")]
+use crate::{
+    data::{
+        StylusTool, ToucheData,
+        capabilities::{
+            Capabilities, FEATURE_BUTTONS, FEATURE_ERASER, FEATURE_HOVER, FEATURE_STYLUS,
+            FEATURE_TILT,
+        },
+    },
+    error::Error,
+    sink::{InputSink, SinkSpec},
+};
 
+use area::AreaMapping;
+#[cfg(target_os = "linux")]
+use area::AreaTransform;
 #[cfg(target_os = "linux")]
 use evdev::{
     uinput::VirtualDevice, AbsInfo, AbsoluteAxisCode, AbsoluteAxisEvent, AttributeSet, BusType,
     InputEvent, InputId, KeyCode, KeyEvent, PropType, UinputAbsSetup,
 };
+#[cfg(target_os = "linux")]
+use log::{error, info, trace};
+use pressure::PressureMapping;
+
+/// Per-profile tuning of the virtual tablet.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TabletSettings {
+    /// device name, the session label gets appended to it
+    pub(crate) name: String,
+    pub(crate) vendor_id: u16,
+    pub(crate) product_id: u16,
+    pub(crate) pressure: PressureMapping,
+    pub(crate) area: AreaMapping,
+    /// `ABS_DISTANCE` maximum
+    pub(crate) distance_resolution: i32,
+}
+
+impl Default for TabletSettings {
+    fn default() -> Self {
+        TabletSettings {
+            name: "touchetab".to_owned(),
+            vendor_id: 0x5120,
+            product_id: 0x0001,
+            pressure: PressureMapping::default(),
+            area: AreaMapping::default(),
+            distance_resolution: 1024,
+        }
+    }
+}
+
+/// units per radian, for axes reported in degrees
+#[cfg(target_os = "linux")]
+const TILT_RESOLUTION: i32 = 57;
 
 #[cfg(target_os = "linux")]
 pub(crate) struct GraphicsTabletDevice {
     device: VirtualDevice,
+    tilt: bool,
+    hover: bool,
+    buttons: bool,
+    eraser: bool,
+    distance_resolution: i32,
+    /// tool currently in proximity
+    tool: Option<StylusTool>,
+    last_stylus_frame: Instant,
+    /// how long the pen may stay silent before it's forced out of proximity
+    proximity_timeout: Duration,
+    pressure: PressureMapping,
+    area: AreaTransform,
 }
 
 #[cfg(target_os = "linux")]
 impl GraphicsTabletDevice {
-    pub(crate) fn new(width: i32, height: i32) -> io::Result<GraphicsTabletDevice> {
+    pub(crate) fn new(
+        name: &str,
+        width: i32,
+        height: i32,
+        capabilities: &Capabilities,
+        settings: &TabletSettings,
+        proximity_timeout: Duration,
+    ) -> io::Result<GraphicsTabletDevice> {
         println!("device setup. width {} height {}", width, height);
+        let pressure = settings.pressure.clone();
+        let area = AreaTransform::new(&settings.area, width, height);
+        let (axis_width, axis_height) = area.axis_max;
+        let tilt = capabilities.has(FEATURE_TILT);
+        let hover = capabilities.has(FEATURE_HOVER);
+        let buttons = capabilities.has(FEATURE_BUTTONS);
+        let eraser = capabilities.has(FEATURE_ERASER);
+
         let mut touche_tablet_keys: AttributeSet<KeyCode> = AttributeSet::new();
         touche_tablet_keys.insert(KeyCode::BTN_STYLUS);
         touche_tablet_keys.insert(KeyCode::BTN_TOOL_PEN);
         touche_tablet_keys.insert(KeyCode::BTN_TOUCH);
+        if buttons {
+            touche_tablet_keys.insert(KeyCode::BTN_STYLUS2);
+        }
+        if eraser {
+            touche_tablet_keys.insert(KeyCode::BTN_TOOL_RUBBER);
+        }
 
         let mut touche_tablet_props: AttributeSet<PropType> = AttributeSet::new();
         touche_tablet_props.insert(PropType::DIRECT);
         touche_tablet_props.insert(PropType::POINTER);
 
-        let device = evdev::uinput::VirtualDevice::builder()?
-            .name("touchetab")
+        let mut builder = evdev::uinput::VirtualDevice::builder()?
+            .name(name)
             .with_properties(&touche_tablet_props)?
             .with_keys(&touche_tablet_keys)?
             .with_absolute_axis(&UinputAbsSetup::new(
                 AbsoluteAxisCode::ABS_X,
-                AbsInfo::new(0, 0, width, 0, 0, 100),
+                AbsInfo::new(0, 0, axis_width, 0, 0, 100),
             ))?
             .with_absolute_axis(&UinputAbsSetup::new(
                 AbsoluteAxisCode::ABS_Y,
-                AbsInfo::new(0, 0, height, 0, 0, 100),
+                AbsInfo::new(0, 0, axis_height, 0, 0, 100),
             ))?
             .with_absolute_axis(&UinputAbsSetup::new(
                 AbsoluteAxisCode::ABS_PRESSURE,
-                AbsInfo::new(0, 0, 4096, 0, 0, 100),
-            ))?
-            .with_absolute_axis(&UinputAbsSetup::new(
+                AbsInfo::new(0, 0, pressure.resolution, 0, 0, 100),
+            ))?;
+        if tilt {
+            // tilt is in degrees, and the kernel wants resolution in units per radian
+            builder = builder
+                .with_absolute_axis(&UinputAbsSetup::new(
+                    AbsoluteAxisCode::ABS_TILT_X,
+                    AbsInfo::new(0, -90, 90, 0, 0, TILT_RESOLUTION),
+                ))?
+                .with_absolute_axis(&UinputAbsSetup::new(
+                    AbsoluteAxisCode::ABS_TILT_Y,
+                    AbsInfo::new(0, -90, 90, 0, 0, TILT_RESOLUTION),
+                ))?
+                // pen rotation lives on ABS_Z, same as on Wacom's Art Pen
+                .with_absolute_axis(&UinputAbsSetup::new(
+                    AbsoluteAxisCode::ABS_Z,
+                    AbsInfo::new(0, -180, 180, 0, 0, TILT_RESOLUTION),
+                ))?;
+        }
+        if hover {
+            builder = builder.with_absolute_axis(&UinputAbsSetup::new(
                 AbsoluteAxisCode::ABS_DISTANCE,
-                AbsInfo::new(0, 0, 1024, 0, 0, 100),
-            ))?
-            .input_id(InputId::new(BusType::BUS_USB, 0x5120, 0x0001, 0x1))
+                AbsInfo::new(0, 0, settings.distance_resolution, 0, 0, 100),
+            ))?;
+        }
+        let device = builder
+            .input_id(InputId::new(
+                BusType::BUS_USB,
+                settings.vendor_id,
+                settings.product_id,
+                0x1,
+            ))
             .build()?;
-        Ok(GraphicsTabletDevice { device })
+        Ok(GraphicsTabletDevice {
+            device,
+            tilt,
+            hover,
+            buttons,
+            eraser,
+            distance_resolution: settings.distance_resolution,
+            tool: None,
+            last_stylus_frame: Instant::now(),
+            proximity_timeout,
+            pressure,
+            area,
+        })
+    }
+
+    /// Takes the pen out of proximity if the phone went quiet for longer than `timeout`.
+    /// Phones don't always say goodbye - the app may get backgrounded mid-hover.
+    pub(crate) fn watchdog(&mut self, timeout: Duration) -> Result<(), io::Error> {
+        if self.last_stylus_frame.elapsed() < timeout {
+            return Ok(());
+        }
+        if self.tool.is_some() {
+            trace!("no stylus frames for {:?}, forcing proximity out", timeout);
+        }
+        self.release()
+    }
+
+    /// Takes the pen out of proximity right away, if it's in.
+    pub(crate) fn release(&mut self) -> Result<(), io::Error> {
+        match self.tool.take() {
+            Some(tool) => self.device.emit(&self.proximity_out_events(tool)),
+            None => Ok(()),
+        }
+    }
+
+    fn proximity_out_events(&self, tool: StylusTool) -> Vec<InputEvent> {
+        let mut events = vec![
+            *KeyEvent::new(KeyCode::BTN_TOUCH, 0),
+            *AbsoluteAxisEvent::new(AbsoluteAxisCode::ABS_PRESSURE, 0),
+        ];
+        if self.buttons {
+            events.push(*KeyEvent::new(KeyCode::BTN_STYLUS, 0));
+            events.push(*KeyEvent::new(KeyCode::BTN_STYLUS2, 0));
+        }
+        events.push(*KeyEvent::new(tool_key(tool), 0));
+        events
     }
 
     pub(crate) fn emit(&mut self, touche_data: &[ToucheData]) -> Result<(), io::Error> {
@@ -58,32 +215,100 @@ impl GraphicsTabletDevice {
                 ToucheData::ScreenSize { .. } => {
                     // screen size event - do nothing
                 }
-                ToucheData::TouchFrame { .. } => {}
+                ToucheData::TouchFrame { .. }
+                | ToucheData::Capabilities(_)
+                | ToucheData::Rotation(_) => {}
                 ToucheData::StylusFrame {
                     x,
                     y,
                     pressed,
                     pressure,
+                    tilt_x,
+                    tilt_y,
+                    orientation,
+                    distance,
+                    tool,
+                    primary_button,
+                    secondary_button,
+                    in_proximity,
                 } => {
-                    let tool_pen_event = *KeyEvent::new(KeyCode::BTN_TOOL_PEN, 1);
-                    let x_event = *AbsoluteAxisEvent::new(AbsoluteAxisCode::ABS_X, *x);
-                    let y_event = *AbsoluteAxisEvent::new(AbsoluteAxisCode::ABS_Y, *y);
-                    let touch_event = *KeyEvent::new(KeyCode::BTN_TOUCH, (*pressed).into());
+                    self.last_stylus_frame = Instant::now();
+                    let (x, y) = self.area.apply(*x, *y);
+                    if !*in_proximity {
+                        if let Some(old_tool) = self.tool.take() {
+                            tablet_events.push(x_event(x));
+                            tablet_events.push(y_event(y));
+                            tablet_events.append(&mut self.proximity_out_events(old_tool));
+                        }
+                        continue;
+                    }
+
+                    let tool = if self.eraser { *tool } else { StylusTool::Pen };
+                    if let Some(old_tool) = self.tool.filter(|old_tool| *old_tool != tool) {
+                        // the old tool has to leave proximity in its own report,
+                        // otherwise clients see two tools at once
+                        tablet_events.append(&mut self.proximity_out_events(old_tool));
+                        self.device.emit(&tablet_events)?;
+                        tablet_events.clear();
+                    }
+                    self.tool = Some(tool);
+
+                    let tool_pen_event = *KeyEvent::new(tool_key(tool), 1);
+                    // a feather-light touch below the activation threshold is still hovering
+                    let touching = *pressed && self.pressure.is_active(*pressure);
+                    let touch_event = *KeyEvent::new(KeyCode::BTN_TOUCH, touching.into());
 
                     tablet_events.push(tool_pen_event);
-                    tablet_events.push(x_event);
-                    tablet_events.push(y_event);
+                    tablet_events.push(x_event(x));
+                    tablet_events.push(y_event(y));
                     tablet_events.push(touch_event);
 
-                    let pressure_int = if let Some(pressure_value) = pressure {
-                        (pressure_value * 4096.0) as i32 // Assuming max pressure is 4096
-                    } else {
-                        0
+                    let pressure_int = match pressure {
+                        Some(pressure_value) if touching => self.pressure.map(*pressure_value),
+                        _ => 0,
                     };
                     tablet_events.push(*AbsoluteAxisEvent::new(
                         AbsoluteAxisCode::ABS_PRESSURE,
                         pressure_int,
                     ));
+
+                    if self.buttons {
+                        tablet_events.push(*KeyEvent::new(
+                            KeyCode::BTN_STYLUS,
+                            (*primary_button).into(),
+                        ));
+                        tablet_events.push(*KeyEvent::new(
+                            KeyCode::BTN_STYLUS2,
+                            (*secondary_button).into(),
+                        ));
+                    }
+
+                    if self.tilt {
+                        let axes = [
+                            (AbsoluteAxisCode::ABS_TILT_X, tilt_x, 90.0),
+                            (AbsoluteAxisCode::ABS_TILT_Y, tilt_y, 90.0),
+                            (AbsoluteAxisCode::ABS_Z, orientation, 180.0),
+                        ];
+                        for (axis, value, limit) in axes {
+                            if let Some(value) = value {
+                                tablet_events.push(*AbsoluteAxisEvent::new(
+                                    axis,
+                                    value.clamp(-limit, limit).round() as i32,
+                                ));
+                            }
+                        }
+                    }
+
+                    if self.hover {
+                        // touching the screen is zero distance, whatever the phone says
+                        let distance = if *pressed { Some(0.0) } else { *distance };
+                        if let Some(distance) = distance {
+                            tablet_events.push(*AbsoluteAxisEvent::new(
+                                AbsoluteAxisCode::ABS_DISTANCE,
+                                (distance.clamp(0.0, 1.0) * self.distance_resolution as f32) as i32,
+                            ));
+                        }
+                    }
                 }
             }
         }
@@ -93,3 +318,77 @@ impl GraphicsTabletDevice {
         Result::Ok(())
     }
 }
+
+#[cfg(target_os = "linux")]
+impl InputSink for GraphicsTabletDevice {
+    fn create(spec: &SinkSpec) -> Result<Option<Self>, Error> {
+        if !spec.capabilities.has(FEATURE_STYLUS) {
+            info!("phone has no stylus, skipping the graphics tablet");
+            return Ok(None);
+        }
+        let settings = &spec.options.tablet;
+        GraphicsTabletDevice::new(
+            &format!("{}-{}", settings.name, spec.label),
+            spec.width,
+            spec.height,
+            spec.capabilities,
+            settings,
+            spec.options.proximity_timeout,
+        )
+        .map(Some)
+        .map_err(|source| Error::Uinput {
+            device: "graphics tablet",
+            source,
+        })
+    }
+
+    fn consume(&mut self, frame: &[ToucheData]) -> Result<(), Error> {
+        // a hiccup of the tablet isn't worth dropping the phone over
+        if let Err(e) = self.emit(frame) {
+            error!("graphics tablet event processing error!");
+            info!("error logs:\n{}", e);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.watchdog(self.proximity_timeout)
+            .map_err(|source| Error::Emit {
+                device: "graphics tablet",
+                source,
+            })
+    }
+
+    /// Swaps pressure and area settings of a live device. Axis ranges stay
+    /// as they were, changing those takes a new device.
+    fn update(&mut self, spec: &SinkSpec) {
+        self.pressure = spec.options.tablet.pressure.clone();
+        self.area = AreaTransform::new(&spec.options.tablet.area, spec.width, spec.height);
+        self.proximity_timeout = spec.options.proximity_timeout;
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        self.release().map_err(|source| Error::Emit {
+            device: "graphics tablet",
+            source,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn tool_key(tool: StylusTool) -> KeyCode {
+    match tool {
+        StylusTool::Pen => KeyCode::BTN_TOOL_PEN,
+        StylusTool::Eraser => KeyCode::BTN_TOOL_RUBBER,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn x_event(x: f32) -> InputEvent {
+    *AbsoluteAxisEvent::new(AbsoluteAxisCode::ABS_X, x.round() as i32)
+}
+
+#[cfg(target_os = "linux")]
+fn y_event(y: f32) -> InputEvent {
+    *AbsoluteAxisEvent::new(AbsoluteAxisCode::ABS_Y, y.round() as i32)
+}
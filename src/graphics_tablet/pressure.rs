@@ -0,0 +1,314 @@
+use serde::Deserialize;
+
+/// Shape of the pressure response, applied to normalized pressure in `0.0..=1.0`.
+///
+/// In the config it's `"linear"`, `{ gamma = 1.5 }`,
+/// `{ bezier = { p1 = [0.4, 0.0], p2 = [0.6, 1.0] } }`
+/// or `{ piecewise = [[0.0, 0.0], [0.3, 0.6], [1.0, 1.0]] }`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PressureCurve {
+    Linear,
+    /// `output = input ^ gamma`, above 1.0 feels harder, below 1.0 feels softer
+    Gamma(f32),
+    /// cubic Bézier from (0, 0) to (1, 1), the same idea as CSS' `cubic-bezier()`
+    Bezier {
+        p1: (f32, f32),
+        p2: (f32, f32),
+    },
+    /// straight lines between `(input, output)` points sorted by input
+    Piecewise(Vec<(f32, f32)>),
+}
+
+impl PressureCurve {
+    /// Whether the curve makes sense, and why not if it doesn't.
+    pub(crate) fn check(&self) -> Result<(), &'static str> {
+        let unit = |value: f32| (0.0..=1.0).contains(&value);
+        match self {
+            PressureCurve::Linear => Ok(()),
+            PressureCurve::Gamma(gamma) if !(gamma.is_finite() && *gamma > 0.0) => {
+                Err("needs a gamma above 0")
+            }
+            PressureCurve::Gamma(_) => Ok(()),
+            PressureCurve::Bezier { p1, p2 } if !unit(p1.0) || !unit(p2.0) => {
+                Err("needs control points with x between 0 and 1")
+            }
+            PressureCurve::Bezier { .. } => Ok(()),
+            PressureCurve::Piecewise(points) if points.is_empty() => Err("needs points"),
+            PressureCurve::Piecewise(points)
+                if !points.iter().all(|(x, y)| unit(*x) && unit(*y)) =>
+            {
+                Err("needs points between 0 and 1")
+            }
+            PressureCurve::Piecewise(points)
+                if points.windows(2).any(|pair| pair[0].0 > pair[1].0) =>
+            {
+                Err("needs points sorted by input")
+            }
+            PressureCurve::Piecewise(_) => Ok(()),
+        }
+    }
+
+    fn apply(&self, input: f32) -> f32 {
+        match self {
+            PressureCurve::Linear => input,
+            PressureCurve::Gamma(gamma) => input.powf(gamma.max(f32::EPSILON)),
+            PressureCurve::Bezier { p1, p2 } => bezier(*p1, *p2, input),
+            PressureCurve::Piecewise(points) => piecewise(points, input),
+        }
+    }
+}
+
+/// Turns raw phone pressure into `ABS_PRESSURE` values.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PressureMapping {
+    pub(crate) curve: PressureCurve,
+    /// raw pressure at or below this counts as no pressure at all
+    pub(crate) min: f32,
+    /// raw pressure at or above this counts as full pressure
+    pub(crate) max: f32,
+    /// mapped pressure the pen needs before it counts as touching
+    pub(crate) activation: f32,
+    /// `ABS_PRESSURE` maximum
+    pub(crate) resolution: i32,
+}
+
+impl Default for PressureMapping {
+    fn default() -> Self {
+        PressureMapping {
+            curve: PressureCurve::Linear,
+            min: 0.0,
+            max: 1.0,
+            activation: 0.0,
+            resolution: 4096,
+        }
+    }
+}
+
+impl PressureMapping {
+    /// Maps raw pressure to `0.0..=1.0`, thresholds and curve included.
+    pub(crate) fn normalize(&self, raw: f32) -> f32 {
+        let range = self.max - self.min;
+        let input = if range > f32::EPSILON {
+            ((raw - self.min) / range).clamp(0.0, 1.0)
+        } else {
+            // min and max are the same, so it's all or nothing
+            if raw >= self.max { 1.0 } else { 0.0 }
+        };
+        self.curve.apply(input).clamp(0.0, 1.0)
+    }
+
+    /// Maps raw pressure to an `ABS_PRESSURE` value in `0..=resolution`.
+    pub(crate) fn map(&self, raw: f32) -> i32 {
+        (self.normalize(raw) * self.resolution as f32).round() as i32
+    }
+
+    /// Whether `raw` pressure is enough to count as touching.
+    /// Phones that don't report pressure at all are always active.
+    pub(crate) fn is_active(&self, raw: Option<f32>) -> bool {
+        raw.is_none_or(|raw| self.normalize(raw) >= self.activation)
+    }
+}
+
+fn bezier(p1: (f32, f32), p2: (f32, f32), input: f32) -> f32 {
+    let at = |t: f32, a: f32, b: f32| {
+        let u = 1.0 - t;
+        3.0 * u * u * t * a + 3.0 * u * t * t * b + t * t * t
+    };
+    // x(t) is monotonic as long as the control points stay within 0..=1,
+    // so plain bisection finds the t for our input
+    let (x1, x2) = (p1.0.clamp(0.0, 1.0), p2.0.clamp(0.0, 1.0));
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..32 {
+        let mid = (low + high) / 2.0;
+        if at(mid, x1, x2) < input {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    at((low + high) / 2.0, p1.1, p2.1)
+}
+
+fn piecewise(points: &[(f32, f32)], input: f32) -> f32 {
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return input;
+    };
+    if input <= first.0 {
+        return first.1;
+    }
+    for pair in points.windows(2) {
+        let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+        if input <= x1 {
+            if x1 - x0 <= f32::EPSILON {
+                return y1;
+            }
+            return y0 + (y1 - y0) * (input - x0) / (x1 - x0);
+        }
+    }
+    last.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn check_refuses_curves_that_make_no_sense() {
+        assert_eq!(PressureCurve::Linear.check(), Ok(()));
+        assert!(PressureCurve::Gamma(-1.0).check().is_err());
+        assert!(PressureCurve::Gamma(f32::NAN).check().is_err());
+        assert!(
+            PressureCurve::Bezier {
+                p1: (1.5, 0.0),
+                p2: (0.5, 1.0)
+            }
+            .check()
+            .is_err()
+        );
+        // control points may overshoot upwards, only x has to stay put
+        assert_eq!(
+            PressureCurve::Bezier {
+                p1: (0.5, 1.5),
+                p2: (0.5, -0.5)
+            }
+            .check(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn check_wants_sorted_piecewise_points_within_range() {
+        assert_eq!(
+            PressureCurve::Piecewise(vec![]).check(),
+            Err("needs points")
+        );
+        assert_eq!(
+            PressureCurve::Piecewise(vec![(0.0, 0.0), (1.0, 1.2)]).check(),
+            Err("needs points between 0 and 1")
+        );
+        assert_eq!(
+            PressureCurve::Piecewise(vec![(0.6, 0.0), (0.3, 1.0)]).check(),
+            Err("needs points sorted by input")
+        );
+    }
+
+    #[test]
+    fn linear_changes_nothing() {
+        for input in [0.0, 0.25, 1.0] {
+            assert_eq!(PressureCurve::Linear.apply(input), input);
+        }
+    }
+
+    #[test]
+    fn gamma_above_one_feels_harder() {
+        let curve = PressureCurve::Gamma(2.0);
+
+        assert!(close(curve.apply(0.5), 0.25));
+        assert_eq!(curve.apply(0.0), 0.0);
+        assert_eq!(curve.apply(1.0), 1.0);
+    }
+
+    #[test]
+    fn bezier_goes_from_corner_to_corner() {
+        let curve = PressureCurve::Bezier {
+            p1: (0.4, 0.0),
+            p2: (0.6, 1.0),
+        };
+
+        assert!(close(curve.apply(0.0), 0.0));
+        assert!(close(curve.apply(1.0), 1.0));
+        // symmetric control points meet the diagonal halfway
+        assert!(close(curve.apply(0.5), 0.5));
+        assert!(curve.apply(0.25) < 0.25);
+        assert!(curve.apply(0.75) > 0.75);
+    }
+
+    #[test]
+    fn bezier_with_diagonal_control_points_is_linear() {
+        let curve = PressureCurve::Bezier {
+            p1: (1.0 / 3.0, 1.0 / 3.0),
+            p2: (2.0 / 3.0, 2.0 / 3.0),
+        };
+
+        for input in [0.1, 0.3, 0.7, 0.9] {
+            assert!(close(curve.apply(input), input));
+        }
+    }
+
+    #[test]
+    fn piecewise_interpolates_between_points() {
+        let curve = PressureCurve::Piecewise(vec![(0.2, 0.1), (0.5, 0.7), (1.0, 1.0)]);
+
+        // flat before the first point and after the last
+        assert_eq!(curve.apply(0.0), 0.1);
+        assert!(close(curve.apply(0.35), 0.4));
+        assert!(close(curve.apply(0.75), 0.85));
+        assert_eq!(curve.apply(1.0), 1.0);
+    }
+
+    #[test]
+    fn piecewise_steps_at_repeated_inputs() {
+        let curve = PressureCurve::Piecewise(vec![(0.0, 0.0), (0.5, 0.2), (0.5, 0.8), (1.0, 1.0)]);
+
+        assert!(close(curve.apply(0.5), 0.2));
+        assert!(close(curve.apply(0.75), 0.9));
+    }
+
+    #[test]
+    fn normalize_stretches_min_to_max_over_the_whole_range() {
+        let mapping = PressureMapping {
+            min: 0.2,
+            max: 0.6,
+            ..PressureMapping::default()
+        };
+
+        assert_eq!(mapping.normalize(0.1), 0.0);
+        assert!(close(mapping.normalize(0.4), 0.5));
+        assert_eq!(mapping.normalize(0.9), 1.0);
+    }
+
+    #[test]
+    fn normalize_is_all_or_nothing_when_min_is_max() {
+        let mapping = PressureMapping {
+            min: 0.5,
+            max: 0.5,
+            ..PressureMapping::default()
+        };
+
+        assert_eq!(mapping.normalize(0.49), 0.0);
+        assert_eq!(mapping.normalize(0.5), 1.0);
+    }
+
+    #[test]
+    fn map_scales_to_the_resolution() {
+        let mapping = PressureMapping {
+            curve: PressureCurve::Gamma(2.0),
+            resolution: 1000,
+            ..PressureMapping::default()
+        };
+
+        assert_eq!(mapping.map(0.0), 0);
+        assert_eq!(mapping.map(0.5), 250);
+        assert_eq!(mapping.map(1.0), 1000);
+        assert_eq!(mapping.map(7.0), 1000);
+    }
+
+    #[test]
+    fn activation_is_checked_after_the_curve() {
+        let mapping = PressureMapping {
+            curve: PressureCurve::Gamma(2.0),
+            activation: 0.2,
+            ..PressureMapping::default()
+        };
+
+        // 0.4 squared is under the threshold, 0.5 squared isn't
+        assert!(!mapping.is_active(Some(0.4)));
+        assert!(mapping.is_active(Some(0.5)));
+        assert!(mapping.is_active(None));
+    }
+}
@@ -1,100 +1,300 @@
+// the virtual devices are uinput only, so elsewhere most of what feeds them goes unused
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+mod adb;
 mod aoa;
+mod cli;
+mod config;
 mod data;
 mod driver;
+mod error;
 mod graphics_tablet;
+mod net;
+mod permissions;
+mod recording;
+mod session;
+mod sink;
+mod source;
 mod touchpad;
 
-use std::{io::Write, time::Duration};
-
-use aoa::{
-    AoaDevice,
-    utils::{get_aoa_version, introduce_host, is_aoa, make_aoa},
+use std::{
+    collections::HashSet, fs, io::Write, path::PathBuf, process::ExitCode, sync::Arc,
+    time::Duration,
 };
+
+use adb::has_adb_interface;
+use aoa::utils::{get_aoa_version, introduce_host, is_aoa, make_aoa};
 use chrono::Utc;
-use driver::driver_loop;
+use clap::Parser;
+use cli::{Cli, Command, ProfileArgs, RunArgs, daemon};
+use config::{
+    Accessory, Config,
+    live::{self, LiveConfig},
+};
+use error::Error;
 use futures_lite::stream;
-use log::{debug, error, info};
-use nusb::{hotplug::HotplugEvent, watch_devices};
-
-fn main() {
-    let _ = env_logger::builder()
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{}|{}|{}: {}",
-                Utc::now().format("%Y-%m-%d %H:%M:%S.%3f"),
-                record.module_path().unwrap_or("NO_MODULE"),
-                record.level(),
-                record.args()
-            )
-        })
-        .try_init();
-    for event in stream::block_on(watch_devices().unwrap()) {
+use log::{debug, error, info, warn};
+use nusb::{DeviceInfo, hotplug::HotplugEvent, list_devices, watch_devices};
+use permissions::looks_like_phone;
+use session::SessionManager;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let mut logger = env_logger::builder();
+    logger.format(|buf, record| {
+        writeln!(
+            buf,
+            "{}|{}|{}: {}",
+            Utc::now().format("%Y-%m-%d %H:%M:%S.%3f"),
+            record.module_path().unwrap_or("NO_MODULE"),
+            record.level(),
+            record.args()
+        )
+    });
+    if let Some(level) = cli.log_level {
+        logger.filter_level(level);
+    }
+    let _ = logger.try_init();
+
+    match cli.command.unwrap_or(Command::Run(cli.run)) {
+        Command::Run(args) => return start(&args, None),
+        Command::Record { output, run } => return start(&run, Some(output)),
+        Command::Replay {
+            file,
+            speed,
+            profile,
+        } => {
+            let Some((config, _)) = load_config(&profile) else {
+                return ExitCode::FAILURE;
+            };
+            if let Err(e) = driver::replay::replay(&file, config, speed) {
+                error!("replay failed! {}", e);
+                info!("error logs:\n{:?}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::Pair => {
+            if let Err(e) = net::pairing::open_window() {
+                error!("failed to start pairing! {}", e);
+                info!("error logs:\n{:?}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::TestClient(args) => {
+            if let Err(e) = net::test_client::test_client(&args) {
+                error!("{}", e);
+                info!("error logs:\n{:?}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::ListDevices { all } => {
+            if let Err(e) = cli::list_devices(all) {
+                error!("failed to list USB devices!");
+                info!("error logs:\n{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::Doctor => {
+            if !permissions::doctor::doctor() {
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::Udev { path } => {
+            if let Err(e) = permissions::udev::udev(&path) {
+                error!("failed to generate udev rules!");
+                info!("error logs:\n{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// `run` and `record`, in the foreground or the background.
+fn start(args: &RunArgs, record: Option<PathBuf>) -> ExitCode {
+    if args.daemon {
+        match daemon::daemonize() {
+            Ok(pid) => {
+                println!(
+                    "running in the background as pid {}, logging to {}",
+                    pid,
+                    daemon::log_path().display()
+                );
+            }
+            Err(e) => {
+                error!("failed to start in the background!");
+                info!("error logs:\n{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let Some((config, path)) = load_config(&args.profile) else {
+        return ExitCode::FAILURE;
+    };
+    run(args, config, path, record);
+    ExitCode::SUCCESS
+}
+
+/// The config with command-line overrides applied, and where it came from.
+fn load_config(args: &ProfileArgs) -> Option<(Config, Option<PathBuf>)> {
+    let path = config::find(args.config.as_deref());
+    match Config::load(path.as_deref()) {
+        Ok(mut config) => {
+            config.overrides = args.overrides();
+            Some((config, path))
+        }
+        Err(e) => {
+            error!("{}", e);
+            info!("error logs:\n{:?}", e);
+            None
+        }
+    }
+}
+
+fn run(args: &RunArgs, config: Config, path: Option<PathBuf>, record: Option<PathBuf>) {
+    let config = Arc::new(LiveConfig::new(config));
+    let _watcher = match &path {
+        Some(path) => live::watch(path.clone(), Arc::clone(&config))
+            .inspect_err(|e| {
+                warn!("failed to watch the config, changes need a restart!");
+                info!("error logs:\n{}", e);
+            })
+            .ok(),
+        // no config yet, but one created later still gets picked up
+        None => config::default_paths().into_iter().next().and_then(|path| {
+            // the directory has to be there to be watched
+            fs::create_dir_all(path.parent()?).ok()?;
+            live::watch(path, Arc::clone(&config)).ok()
+        }),
+    };
+
+    // start watching before the scan, so nothing plugged in mid-scan gets lost
+    let hotplug = match watch_devices() {
+        Ok(hotplug) => hotplug,
+        Err(e) => {
+            error!("failed to watch USB devices!");
+            info!("error logs:\n{}", e);
+            return;
+        }
+    };
+    let mut sessions = SessionManager::new(Arc::clone(&config), record);
+
+    let network = config.get().network.clone();
+    if let Some(addr) = args.listen.or(network.listen)
+        && let Err(e) = net::listener::spawn(addr, network.psk.as_deref(), sessions.remote())
+    {
+        error!("{}", e);
+        info!("error logs:\n{:?}", e);
+    }
+
+    // devices plugged in before the driver started never show up as hotplug events
+    let mut scanned = HashSet::new();
+    match list_devices() {
+        Ok(devices) => {
+            for device_info in devices {
+                scanned.insert(device_info.id());
+                handle_device(device_info, &mut sessions, args, &config.get());
+            }
+        }
+        Err(e) => {
+            error!("failed to list connected USB devices!");
+            info!("error logs:\n{}", e);
+        }
+    }
+
+    for event in stream::block_on(hotplug) {
+        if let HotplugEvent::Disconnected(id) = event {
+            scanned.remove(&id);
+            sessions.disconnected(id);
+            continue;
+        }
         info!("new USB device connected");
         if let HotplugEvent::Connected(device_info) = event {
-            std::thread::sleep(Duration::from_millis(100));
-
-            debug!("connected device product_id: {}", device_info.product_id());
-
-            if is_aoa(&device_info) {
-                let aoa_device = match AoaDevice::new(device_info) {
-                    Ok(device) => device,
-                    Err(_) => {
-                        error!("failed to create AOA device!");
-                        continue;
-                    }
-                };
-                info!("AOA device detected. starting driver loop...");
-                match driver_loop(aoa_device) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        info!("if at first you don't succeed, die, die again!");
-                    }
-                };
-            } else {
-                info!("searching for Android device...");
-                if let Ok(handle) = device_info.open() {
-                    std::thread::sleep(Duration::from_millis(1000));
-                    // TODO: make it claim the interface
-                    // outside Unix platforms
-
-                    // AOA stage 1 - determine AOA version
-                    let data_stage_1 = get_aoa_version(&handle).unwrap_or_default();
-                    info!("getting AOA version");
-                    if !data_stage_1.first().is_some_and(|it| (1..=2).contains(it))
-                    /* require AOA v1+ */
-                    {
-                        continue;
-                    }
-                    // AOA stage 2 - introduce the driver to the Android device
-                    info!("introducing the driver");
-
-                    let manufacturer_name = "bpavuk";
-                    let model_name = "touche";
-                    let description = "making your phone a touchepad and graphics tablet";
-                    let version = "v0"; // TODO: change to v1 once it's done
-                    let uri = "what://"; // TODO
-                    let serial_number = "528491"; // have you ever watched Inception?
-
-                    introduce_host(
-                        &handle,
-                        manufacturer_name,
-                        model_name,
-                        description,
-                        version,
-                        uri,
-                        serial_number,
-                    );
-
-                    // AOA stage 3 - make Android your accessory
-                    info!("actually building the AOA device");
-                    let _ = make_aoa(&handle);
-                    let _ = handle.reset();
-                } else {
-                    error!("failed to open the device");
-                    continue;
-                }
+            // the watcher may report devices the scan has already handled
+            if scanned.remove(&device_info.id()) {
+                debug!("device already handled during startup scan");
+                continue;
+            }
+            let current = config.get();
+            std::thread::sleep(Duration::from_millis(current.accessory.hotplug_delay_ms));
+            handle_device(device_info, &mut sessions, args, &current);
+        }
+    }
+}
+
+fn handle_device(
+    device_info: DeviceInfo,
+    sessions: &mut SessionManager,
+    args: &RunArgs,
+    config: &Config,
+) {
+    debug!("connected device product_id: {}", device_info.product_id());
+
+    if !args.selects(&device_info) {
+        debug!("device not picked by --serial or --vendor, skipping");
+    } else if is_aoa(&device_info) {
+        sessions.start(device_info);
+    } else if !looks_like_phone(&device_info) {
+        // keyboards and webcams don't need a second of poking each
+        debug!("doesn't look like a phone, skipping");
+    } else {
+        match make_accessory(&device_info, &config.accessory) {
+            Ok(()) => {}
+            // no accessory mode, but USB debugging gets us there too
+            Err(e @ Error::AoaUnsupported { .. })
+                if config.adb.enabled && has_adb_interface(&device_info) =>
+            {
+                info!("{}, trying adb instead", e);
+                sessions.start_adb(&device_info, &config.adb);
+            }
+            // most USB devices aren't phones, no need to shout about them
+            Err(e @ Error::AoaUnsupported { .. }) => debug!("{}", e),
+            Err(e) => {
+                error!("failed to make an accessory out of the device! {}", e);
+                info!("error logs:\n{:?}", e);
             }
         }
     }
 }
+
+fn make_accessory(device_info: &DeviceInfo, accessory: &Accessory) -> Result<(), Error> {
+    info!("searching for Android device...");
+    let handle = device_info.open().map_err(Error::UsbOpen)?;
+    std::thread::sleep(Duration::from_millis(accessory.open_delay_ms));
+    // TODO: make it claim the interface
+    // outside Unix platforms
+
+    // AOA stage 1 - determine AOA version
+    info!("getting AOA version");
+    let data_stage_1 = get_aoa_version(&handle).unwrap_or_default();
+    let version = data_stage_1.first().copied();
+    if !version.is_some_and(|it| (1..=2).contains(&it))
+    /* require AOA v1+ */
+    {
+        return Err(Error::AoaUnsupported { version });
+    }
+    // AOA stage 2 - introduce the driver to the Android device
+    info!("introducing the driver");
+    introduce_host(
+        &handle,
+        &accessory.manufacturer,
+        &accessory.model,
+        &accessory.description,
+        &accessory.version,
+        &accessory.uri,
+        &accessory.serial,
+    )
+    .map_err(Error::AoaHandshake)?;
+
+    // AOA stage 3 - make Android your accessory
+    info!("actually building the AOA device");
+    make_aoa(&handle).map_err(Error::AoaHandshake)?;
+    // the phone re-enumerates as an accessory on its own, a failed reset changes nothing
+    if let Err(e) = handle.reset() {
+        debug!("device reset failed: {}", e);
+    }
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+use crate::error::Error;
+
+use super::{hex, pairing::noise, unhex};
+
+/// The long-term key pair one end of a connection is known by.
+pub(crate) struct KeyPair {
+    pub(crate) private: Vec<u8>,
+    pub(crate) public: Vec<u8>,
+}
+
+impl KeyPair {
+    /// The key pair saved at `path`, or a new one if there's none yet.
+    /// It's saved as the hex private key and the hex public key, a line each.
+    pub(crate) fn load_or_create(path: &Path) -> Result<KeyPair, Error> {
+        let error = |source| Error::Keys {
+            path: path.to_owned(),
+            source,
+        };
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                let mut lines = text.lines().map(unhex);
+                match (lines.next().flatten(), lines.next().flatten()) {
+                    (Some(private), Some(public)) => Ok(KeyPair { private, public }),
+                    _ => Err(error(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "not a key pair. Move it away to get a new one",
+                    ))),
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let keypair = noise(None)
+                    .generate_keypair()
+                    .map_err(|e| error(io::Error::other(e)))?;
+                let text = format!("{}\n{}\n", hex(&keypair.private), hex(&keypair.public));
+                save(path, &text).map_err(error)?;
+                Ok(KeyPair {
+                    private: keypair.private,
+                    public: keypair.public,
+                })
+            }
+            Err(e) => Err(error(e)),
+        }
+    }
+}
+
+/// Public keys we let in, each under a name: a `<name> <hex key>` line each.
+///
+/// The file is read again every time, so deleting a line is all it takes
+/// to stop trusting a key, even with the driver running.
+pub(crate) struct TrustedKeys {
+    path: PathBuf,
+}
+
+impl TrustedKeys {
+    pub(crate) fn new(path: PathBuf) -> TrustedKeys {
+        TrustedKeys { path }
+    }
+
+    /// The name `key` is trusted under, if it is.
+    pub(crate) fn name_of(&self, key: &[u8]) -> Result<Option<String>, Error> {
+        Ok(self
+            .load()?
+            .into_iter()
+            .find(|(_, trusted)| trusted == key)
+            .map(|(name, _)| name))
+    }
+
+    /// The key trusted under `name`, if there is one.
+    pub(crate) fn key_of(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self
+            .load()?
+            .into_iter()
+            .find(|(trusted, _)| trusted == name)
+            .map(|(_, key)| key))
+    }
+
+    /// Trusts `key` from now on, under `name`, and `key` loses any name it had.
+    /// Returns false without trusting anything if `name` belongs to another key.
+    pub(crate) fn trust(&self, name: &str, key: &[u8]) -> Result<bool, Error> {
+        let mut text = String::new();
+        for (trusted, trusted_key) in self.load()? {
+            if trusted == name && trusted_key != key {
+                return Ok(false);
+            }
+            if trusted_key != key {
+                text.push_str(&format!("{} {}\n", trusted, hex(&trusted_key)));
+            }
+        }
+        text.push_str(&format!("{} {}\n", name, hex(key)));
+        self.save(&text)?;
+        Ok(true)
+    }
+
+    /// Stops trusting whatever key is under `name`.
+    pub(crate) fn forget(&self, name: &str) -> Result<(), Error> {
+        let mut text = String::new();
+        for (trusted, trusted_key) in self.load()? {
+            if trusted != name {
+                text.push_str(&format!("{} {}\n", trusted, hex(&trusted_key)));
+            }
+        }
+        self.save(&text)
+    }
+
+    fn save(&self, text: &str) -> Result<(), Error> {
+        save(&self.path, text).map_err(|source| Error::Keys {
+            path: self.path.clone(),
+            source,
+        })
+    }
+
+    fn load(&self) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(source) => {
+                return Err(Error::Keys {
+                    path: self.path.clone(),
+                    source,
+                });
+            }
+        };
+        let mut keys = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line
+                .split_once(' ')
+                .and_then(|(name, key)| Some((name, unhex(key.trim())?)))
+            {
+                Some((name, key)) => keys.push((name.to_owned(), key)),
+                None => warn!(
+                    "ignoring line {} of {}, it's not `<name> <key>`",
+                    number + 1,
+                    self.path.display()
+                ),
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Writes `text` to `path` so that only we can read it, without ever
+/// leaving a half-written file behind.
+pub(super) fn save(path: &Path, text: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension("partial");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&partial)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(partial, path)
+}
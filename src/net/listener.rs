@@ -0,0 +1,101 @@
+use std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use log::{error, info, warn};
+
+use crate::{error::Error, session::RemoteSessions};
+
+use super::{Framed, TcpSource, keys_dir, pairing::Pairing};
+
+/// Connections that haven't gotten through pairing yet, past this many
+/// new ones are hung up on right away. Each one gets a thread.
+const MAX_UNPAIRED: usize = 8;
+
+/// Goes in front of the label a phone paired with to make its session label.
+const NETWORK_LABEL_PREFIX: &str = "net-";
+
+/// Starts taking phones over TCP on `addr`, in the background.
+/// Fails right away if `addr` can't be listened on.
+pub(crate) fn spawn(
+    addr: SocketAddr,
+    psk: Option<&str>,
+    sessions: RemoteSessions,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).map_err(|source| Error::Listen { addr, source })?;
+    let dir = keys_dir()?;
+    let pairing = Arc::new(Pairing::new(&dir, psk)?);
+    info!("listening for phones on {}", addr);
+    // `pair` has to write its code here, so it has to run as the same user
+    info!("keeping keys and trusted phones in {}", dir.display());
+
+    thread::Builder::new()
+        .name("touche-listener".to_owned())
+        .spawn(move || accept_loop(&listener, &pairing, &sessions))
+        .map_err(Error::Network)?;
+    Ok(())
+}
+
+/// Counts a connection as unpaired until dropped.
+struct Unpaired(Arc<AtomicUsize>);
+
+impl Drop for Unpaired {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn accept_loop(listener: &TcpListener, pairing: &Arc<Pairing>, sessions: &RemoteSessions) {
+    let unpaired = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let guard = Unpaired(Arc::clone(&unpaired));
+        if unpaired.fetch_add(1, Ordering::Relaxed) >= MAX_UNPAIRED {
+            warn!("too many connections pairing at once, hanging up on a new one");
+            continue;
+        }
+        let pairing = Arc::clone(pairing);
+        let sessions = sessions.clone();
+        let spawned = thread::Builder::new()
+            .name("touche-tcp".to_owned())
+            .spawn(move || connection(stream, guard, &pairing, &sessions));
+        if let Err(e) = spawned {
+            error!("failed to spawn a connection thread!");
+            info!("error logs:\n{}", e);
+        }
+    }
+}
+
+fn connection(stream: TcpStream, unpaired: Unpaired, pairing: &Pairing, sessions: &RemoteSessions) {
+    let peer = stream
+        .peer_addr()
+        .map_or("unknown peer".to_owned(), |peer| peer.to_string());
+    info!("{} connected", peer);
+
+    let paired = Framed::new(stream)
+        .map_err(Error::Network)
+        .and_then(|mut framed| Ok((pairing.accept(&mut framed)?, framed)));
+    drop(unpaired);
+    let (paired, framed) = match paired {
+        Ok(paired) => paired,
+        Err(e) => {
+            warn!("rejected {}: {}", peer, e);
+            return;
+        }
+    };
+    // apart from USB serial numbers, in the parking lot and in config profiles
+    let label = format!("{}{}", NETWORK_LABEL_PREFIX, paired.label);
+    info!("{} is {}", peer, label);
+    sessions.run(&mut TcpSource::new(framed), &label);
+}
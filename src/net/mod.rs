@@ -0,0 +1,376 @@
+pub(crate) mod keys;
+pub(crate) mod listener;
+pub(crate) mod pairing;
+pub(crate) mod test_client;
+
+use std::{
+    fmt::Write as _,
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use snow::TransportState;
+use socket2::{SockRef, TcpKeepalive};
+
+use crate::{config, error::Error, source::ToucheSource};
+
+/// Port the driver listens on unless told otherwise.
+pub(crate) const DEFAULT_PORT: u16 = 7420;
+/// Way more than a phone ever sends at once, anything bigger is garbage.
+/// It's also the most Noise takes in one message.
+const MAX_FRAME_LEN: usize = 65535;
+/// What encrypting adds to every frame.
+const TAG_LEN: usize = 16;
+/// How long a peer gets to finish pairing before it's dropped.
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(10);
+/// Quiet time before TCP starts checking the peer is still there. A phone
+/// that drops off Wi-Fi never says goodbye, and this is how we find out.
+const KEEPALIVE_IDLE: Duration = Duration::from_secs(5);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(2);
+const KEEPALIVE_RETRIES: u32 = 3;
+
+/// A TCP stream cut into frames: a u32 LE length, then that many bytes.
+///
+/// Over USB every bulk transfer is a message of its own. TCP has no such
+/// thing, so this puts the boundaries back, and each frame carries exactly
+/// what a bulk transfer would.
+///
+/// Once [`Framed::encrypt`] is called, every frame is a Noise transport message.
+pub(crate) struct Framed {
+    stream: TcpStream,
+    /// bytes of a frame that hasn't fully arrived yet
+    buffer: Vec<u8>,
+    cipher: Option<TransportState>,
+}
+
+impl Framed {
+    pub(crate) fn new(stream: TcpStream) -> io::Result<Framed> {
+        // frames are small and latency is all that matters
+        stream.set_nodelay(true)?;
+        let keepalive = TcpKeepalive::new()
+            .with_time(KEEPALIVE_IDLE)
+            .with_interval(KEEPALIVE_INTERVAL)
+            .with_retries(KEEPALIVE_RETRIES);
+        SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+        Ok(Framed {
+            stream,
+            buffer: Vec::new(),
+            cipher: None,
+        })
+    }
+
+    /// Encrypts every frame from now on, with the keys of a finished handshake.
+    pub(crate) fn encrypt(&mut self, cipher: TransportState) {
+        self.cipher = Some(cipher);
+    }
+
+    /// Another handle on the same connection, to hang it up from elsewhere.
+    pub(crate) fn try_clone_stream(&self) -> io::Result<TcpStream> {
+        self.stream.try_clone()
+    }
+
+    pub(crate) fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let sealed = match &mut self.cipher {
+            Some(cipher) => {
+                let mut sealed = vec![0; frame.len() + TAG_LEN];
+                let len = cipher
+                    .write_message(frame, &mut sealed)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+                sealed.truncate(len);
+                Some(sealed)
+            }
+            None => None,
+        };
+        let frame = sealed.as_deref().unwrap_or(frame);
+        let len = u32::try_from(frame.len())
+            .ok()
+            .filter(|len| *len as usize <= MAX_FRAME_LEN)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "frame too long"))?;
+        let mut message = Vec::with_capacity(4 + frame.len());
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(frame);
+        self.stream.write_all(&message)
+    }
+
+    /// The next frame, or `Ok(None)` if it didn't fully arrive within `timeout`.
+    /// Whatever did arrive is kept for the next call. A closed connection is
+    /// an `UnexpectedEof` error.
+    pub(crate) fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(frame) = self.take_frame()? {
+                return Ok(Some(frame));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.stream.set_read_timeout(Some(deadline - now))?;
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(None);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Like [`Framed::recv`], but a timeout is an error too.
+    pub(crate) fn recv_within(&mut self, timeout: Duration) -> io::Result<Vec<u8>> {
+        self.recv(timeout)?
+            .ok_or_else(|| io::Error::new(ErrorKind::TimedOut, "the peer went quiet"))
+    }
+
+    fn take_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let Some(header) = self.buffer.first_chunk::<4>() else {
+            return Ok(None);
+        };
+        let len = u32::from_le_bytes(*header) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} byte frame, over the limit of {}", len, MAX_FRAME_LEN),
+            ));
+        }
+        if self.buffer.len() < 4 + len {
+            return Ok(None);
+        }
+        let frame = self.buffer[4..4 + len].to_vec();
+        self.buffer.drain(..4 + len);
+        let Some(cipher) = &mut self.cipher else {
+            return Ok(Some(frame));
+        };
+        // a frame that doesn't decrypt was tampered with, or isn't from the peer at all
+        let mut plain = vec![0; frame.len()];
+        let len = cipher
+            .read_message(&frame, &mut plain)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        plain.truncate(len);
+        Ok(Some(plain))
+    }
+}
+
+/// A phone on the other end of a TCP connection, past pairing if there is any.
+pub(crate) struct TcpSource {
+    framed: Framed,
+}
+
+impl TcpSource {
+    pub(crate) fn new(framed: Framed) -> TcpSource {
+        TcpSource { framed }
+    }
+}
+
+impl ToucheSource for TcpSource {
+    fn handshake(&mut self, request: &[u8]) -> Result<Vec<u8>, Error> {
+        self.framed.send(request).map_err(network_error)?;
+        // the phone answers right away, unless it's gone
+        self.framed
+            .recv_within(PAIRING_TIMEOUT)
+            .map_err(network_error)
+    }
+
+    fn read_frame(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+        self.framed.recv(timeout).map_err(network_error)
+    }
+
+    fn send_opcode(&mut self, opcode: &[u8]) -> Result<(), Error> {
+        self.framed.send(opcode).map_err(network_error)
+    }
+}
+
+/// A dropped connection is just the phone going away,
+/// the same as unplugging it.
+fn network_error(e: io::Error) -> Error {
+    match e.kind() {
+        ErrorKind::UnexpectedEof
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::BrokenPipe => Error::Closed,
+        _ => Error::Network(e),
+    }
+}
+
+/// Where the driver keeps its keys, the phones it trusts and the pairing code.
+fn keys_dir() -> Result<PathBuf, Error> {
+    config::config_dir().ok_or_else(|| Error::Keys {
+        path: PathBuf::from("~/.config/touche-driver"),
+        source: io::Error::new(
+            ErrorKind::NotFound,
+            "neither $XDG_CONFIG_HOME nor $HOME is set",
+        ),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        let _ = write!(out, "{:02x}", byte);
+        out
+    })
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::net::pairing::noise;
+
+    const SHORT: Duration = Duration::from_millis(50);
+
+    /// Our end of a connection, and the peer's.
+    fn connection() -> (Framed, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (Framed::new(listener.accept().unwrap().0).unwrap(), peer)
+    }
+
+    /// The keys of both ends of a finished handshake.
+    fn ciphers() -> (TransportState, TransportState) {
+        let keys = noise(None).generate_keypair().unwrap();
+        let mut initiator = noise(None)
+            .local_private_key(&keys.private)
+            .build_initiator()
+            .unwrap();
+        let keys = noise(None).generate_keypair().unwrap();
+        let mut responder = noise(None)
+            .local_private_key(&keys.private)
+            .build_responder()
+            .unwrap();
+        let (mut message, mut payload) = ([0; 1024], [0; 1024]);
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        responder
+            .read_message(&message[..len], &mut payload)
+            .unwrap();
+        let len = responder.write_message(&[], &mut message).unwrap();
+        initiator
+            .read_message(&message[..len], &mut payload)
+            .unwrap();
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        responder
+            .read_message(&message[..len], &mut payload)
+            .unwrap();
+        (
+            initiator.into_transport_mode().unwrap(),
+            responder.into_transport_mode().unwrap(),
+        )
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_frame() {
+        let (mut framed, mut peer) = connection();
+
+        peer.write_all(&[5, 0]).unwrap();
+        assert_eq!(framed.recv(SHORT).unwrap(), None);
+        peer.write_all(&[0, 0, b'h', b'e']).unwrap();
+        assert_eq!(framed.recv(SHORT).unwrap(), None);
+        peer.write_all(b"llo").unwrap();
+
+        assert_eq!(framed.recv(SHORT).unwrap().as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn splits_frames_that_arrive_together() {
+        let (mut framed, mut peer) = connection();
+
+        peer.write_all(&[2, 0, 0, 0, b'h', b'i', 3, 0, 0, 0, b'y', b'o', b'u'])
+            .unwrap();
+
+        assert_eq!(framed.recv(SHORT).unwrap().as_deref(), Some(&b"hi"[..]));
+        assert_eq!(framed.recv(SHORT).unwrap().as_deref(), Some(&b"you"[..]));
+        assert_eq!(framed.recv(SHORT).unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_oversized_frames() {
+        let (mut framed, mut peer) = connection();
+
+        let len = u32::try_from(MAX_FRAME_LEN + 1).unwrap();
+        peer.write_all(&len.to_le_bytes()).unwrap();
+
+        let e = framed.recv(SHORT).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(framed.send(&vec![0; MAX_FRAME_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn reports_a_closed_connection() {
+        let (mut framed, peer) = connection();
+
+        drop(peer);
+
+        let e = framed.recv(SHORT).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        assert!(matches!(network_error(e), Error::Closed));
+    }
+
+    #[test]
+    fn encrypts_both_ways() {
+        let (ours, theirs) = ciphers();
+        let (mut framed, peer) = connection();
+        let mut peer = Framed::new(peer).unwrap();
+        framed.encrypt(ours);
+        peer.encrypt(theirs);
+
+        peer.send(b"hello").unwrap();
+        assert_eq!(framed.recv(SHORT).unwrap().as_deref(), Some(&b"hello"[..]));
+        framed.send(b"hi back").unwrap();
+        assert_eq!(peer.recv(SHORT).unwrap().as_deref(), Some(&b"hi back"[..]));
+    }
+
+    #[test]
+    fn refuses_frames_that_dont_decrypt() {
+        let (ours, _) = ciphers();
+        let (mut framed, mut peer) = connection();
+        framed.encrypt(ours);
+
+        let mut frame = vec![21, 0, 0, 0];
+        frame.extend_from_slice(&[0xaa; 21]);
+        peer.write_all(&frame).unwrap();
+
+        let e = framed.recv(SHORT).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn refuses_frames_that_were_tampered_with() {
+        let (ours, theirs) = ciphers();
+        let (mut framed, peer) = connection();
+        let mut peer = Framed::new(peer).unwrap();
+        framed.encrypt(ours);
+
+        // seal it like the peer would, then flip a bit on the way
+        let mut theirs = theirs;
+        let mut sealed = vec![0; 5 + TAG_LEN];
+        let len = theirs.write_message(b"hello", &mut sealed).unwrap();
+        sealed[0] ^= 1;
+        peer.send(&sealed[..len]).unwrap();
+
+        let e = framed.recv(SHORT).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn hex_goes_both_ways() {
+        assert_eq!(hex(&[0x00, 0x1f, 0xff]), "001fff");
+        assert_eq!(unhex("001fff"), Some(vec![0x00, 0x1f, 0xff]));
+        assert_eq!(unhex("001"), None);
+        assert_eq!(unhex("zz"), None);
+    }
+}
@@ -0,0 +1,751 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{Shutdown, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
+use snow::{Builder, HandshakeState};
+
+use crate::error::Error;
+
+use super::{
+    Framed, MAX_FRAME_LEN, PAIRING_TIMEOUT, hex,
+    keys::{self, KeyPair, TrustedKeys},
+    keys_dir, unhex,
+};
+
+/// Both ends send their long-term key, encrypted, and prove they hold it.
+const NOISE: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Same, but nobody without the pre-shared key gets through the handshake.
+const NOISE_PSK: &str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s";
+/// Goes into the handshake, so both ends agree on what they speak.
+const PROLOGUE: &[u8] = b"touche\t2";
+/// Wrong codes before pairing is off until `touche-driver pair` runs again.
+const MAX_CODE_ATTEMPTS: u32 = 5;
+/// How long `touche-driver pair` lets phones pair for.
+const PAIRING_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// Bytes of the key hash that make up a fingerprint.
+const FINGERPRINT_LEN: usize = 10;
+/// Labels end up in device names and config profile keys.
+const MAX_LABEL_LEN: usize = 32;
+/// How long a phone coming back waits for its old connection to wind down.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Who may connect over the network.
+///
+/// Every connection starts with a Noise handshake, which encrypts everything
+/// after it and tells each end the other's long-term key. A phone whose key is
+/// in `trusted_phones` just connects. A new one pairs with the one-time code
+/// `touche-driver pair` prints, and its key is trusted from then on.
+///
+/// ```text
+/// phone:  P  <label>  <proof>    first time
+///         C                      coming back
+/// driver: OK  <label>  <proof>   or   NO  <reason>
+/// ```
+///
+/// Fields are tab separated. A `<proof>` is the hex HMAC-SHA256 of the handshake
+/// hash under the code, so it's only good for this one connection, and empty
+/// when coming back. The driver answers with a proof of its own, so the phone
+/// knows the driver really has the code.
+///
+/// The code is short, so it has to be kept from anyone who could try every
+/// code against a proof:
+/// - `touche-driver pair` shows the driver's fingerprint along with the code,
+///   and the phone only sends its proof to a driver with that fingerprint.
+///   Someone pretending to be the driver never gets a proof to try codes on.
+/// - The code only works for a few minutes, and a handful of wrong ones turn
+///   pairing off until the user runs `touche-driver pair` again. Someone
+///   pretending to be a phone only gets that many guesses.
+///
+/// `touche-driver pair` and the running driver share the code through the
+/// `pairing` file, next to the keys, which is gone whenever pairing is off.
+pub(crate) struct Pairing {
+    keys: KeyPair,
+    psk: Option<[u8; 32]>,
+    trusted: TrustedKeys,
+    /// the `pairing` file
+    window: PathBuf,
+    state: Mutex<PairingState>,
+    /// signalled whenever a phone stops being connected
+    released: Condvar,
+}
+
+struct PairingState {
+    /// wrong codes since `touche-driver pair` last ran
+    failures: u32,
+    /// the code `failures` counts for, a new one starts over
+    failures_for: String,
+    /// the phones connected right now, with a handle to hang each one up
+    connected: HashMap<String, TcpStream>,
+}
+
+/// A phone that got through pairing. It counts as connected until this is dropped.
+pub(crate) struct Paired<'a> {
+    pairing: &'a Pairing,
+    pub(crate) label: String,
+}
+
+impl Drop for Paired<'_> {
+    fn drop(&mut self) {
+        let mut state = self.pairing.state.lock().unwrap();
+        state.connected.remove(&self.label);
+        self.pairing.released.notify_all();
+    }
+}
+
+impl Pairing {
+    /// Keeps the driver's key pair and the trusted phones in `dir`.
+    pub(crate) fn new(dir: &Path, psk: Option<&str>) -> Result<Pairing, Error> {
+        let keys = KeyPair::load_or_create(&dir.join("identity"))?;
+        info!("this driver's fingerprint is {}", fingerprint(&keys.public));
+        info!("run `touche-driver pair` to pair a new phone");
+        Ok(Pairing {
+            keys,
+            psk: psk.map(psk_hash),
+            trusted: TrustedKeys::new(dir.join("trusted_phones")),
+            window: dir.join("pairing"),
+            state: Mutex::new(PairingState {
+                failures: 0,
+                failures_for: String::new(),
+                connected: HashMap::new(),
+            }),
+            released: Condvar::new(),
+        })
+    }
+
+    /// The driver's side of the handshake and pairing. Nothing the phone
+    /// sends gets past this unless its key is trusted. A phone is only
+    /// connected once at a time, so coming back hangs up its old connection,
+    /// which is likely dead anyway.
+    pub(crate) fn accept(&self, framed: &mut Framed) -> Result<Paired<'_>, Error> {
+        let mut noise = noise(self.psk.as_ref())
+            .local_private_key(&self.keys.private)
+            .build_responder()
+            .map_err(noise_error)?;
+        // -> e
+        receive(framed, &mut noise)?;
+        // <- e, ee, s, es
+        send(framed, &mut noise)?;
+        // -> s, se
+        receive(framed, &mut noise)?;
+        let key = noise
+            .get_remote_static()
+            .expect("XX always sends the static key")
+            .to_vec();
+        let hash = noise.get_handshake_hash().to_vec();
+        framed.encrypt(noise.into_transport_mode().map_err(noise_error)?);
+
+        let request = framed
+            .recv_within(PAIRING_TIMEOUT)
+            .map_err(Error::Network)?;
+        let stream = framed.try_clone_stream().map_err(Error::Network)?;
+        match self.check(&key, &hash, &request, stream) {
+            Ok((label, proof)) => {
+                let paired = Paired {
+                    pairing: self,
+                    label,
+                };
+                framed
+                    .send(format!("OK\t{}\t{}", paired.label, proof).as_bytes())
+                    .map_err(Error::Network)?;
+                Ok(paired)
+            }
+            Err(reason) => {
+                // the phone deserves to know, but a failed send changes nothing
+                let _ = framed.send(format!("NO\t{}", reason).as_bytes());
+                Err(Error::Pairing(reason.to_owned()))
+            }
+        }
+    }
+
+    /// The label the phone with `key` gets, and the driver's proof.
+    /// `stream` is how to hang up on the phone if it connects again.
+    fn check(
+        &self,
+        key: &[u8],
+        hash: &[u8],
+        request: &[u8],
+        stream: TcpStream,
+    ) -> Result<(String, String), &'static str> {
+        let request = std::str::from_utf8(request).map_err(|_| "not a pairing request")?;
+        let mut fields = request.split('\t');
+        let (label, proof) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some("C"), None, None, None) => {
+                let label = self.trusted_label(key)?;
+                (label.ok_or("unknown phone, pair it with the code")?, None)
+            }
+            (Some("P"), Some(label), Some(proof), None) if is_valid_label(label) => {
+                (label.to_owned(), Some(unhex(proof).ok_or("bad proof")?))
+            }
+            (Some("P"), Some(_), Some(_), None) => return Err("bad label"),
+            _ => return Err("not a pairing request"),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let answer = match proof {
+            None => {
+                info!("{} reconnected", label);
+                String::new()
+            }
+            Some(proof) => {
+                let code = open_code(&self.window)?;
+                if prove(&code, "phone", hash).verify_slice(&proof).is_err() {
+                    if state.failures_for != code {
+                        state.failures_for = code;
+                        state.failures = 0;
+                    }
+                    state.failures += 1;
+                    warn!("wrong pairing code from {}", label);
+                    if state.failures >= MAX_CODE_ATTEMPTS {
+                        warn!("too many wrong pairing codes, turning pairing off");
+                        close_window(&self.window);
+                    }
+                    return Err("wrong code");
+                }
+                let answer = prove(&code, "driver", hash).finalize().into_bytes();
+                match self.trusted.trust(&label, key) {
+                    Ok(true) => {}
+                    // the code is still good, the phone can try again under another name
+                    Ok(false) => return Err("another phone goes by that name"),
+                    Err(e) => {
+                        error!("failed to save {} as trusted!", label);
+                        info!("error logs:\n{}", e);
+                        return Err("the driver can't save trusted phones");
+                    }
+                }
+                info!("{} paired", label);
+                // one time means one time
+                close_window(&self.window);
+                hex(&answer)
+            }
+        };
+        if let Some(old) = state.connected.get(&label) {
+            info!("{} connected again, hanging up its old connection", label);
+            // fails if it's already gone, which is just as good
+            let _ = old.shutdown(Shutdown::Both);
+            let (guard, waited) = self
+                .released
+                .wait_timeout_while(state, TAKEOVER_TIMEOUT, |state| {
+                    state.connected.contains_key(&label)
+                })
+                .unwrap();
+            state = guard;
+            if waited.timed_out() {
+                return Err("already connected");
+            }
+        }
+        state.connected.insert(label.clone(), stream);
+        Ok((label, answer))
+    }
+
+    fn trusted_label(&self, key: &[u8]) -> Result<Option<String>, &'static str> {
+        self.trusted.name_of(key).map_err(|e| {
+            error!("failed to read the trusted phones!");
+            info!("error logs:\n{}", e);
+            "the driver can't read its trusted phones"
+        })
+    }
+}
+
+/// `touche-driver pair`: lets a phone pair for the next few minutes, with
+/// a new code. Any code from before stops working.
+pub(crate) fn open_window() -> Result<(), Error> {
+    let dir = keys_dir()?;
+    let keys = KeyPair::load_or_create(&dir.join("identity"))?;
+    let path = dir.join("pairing");
+    let code = new_code().map_err(|source| Error::Keys {
+        path: path.clone(),
+        source,
+    })?;
+    let until = now() + PAIRING_WINDOW.as_secs();
+    keys::save(&path, &format!("{} {}\n", code, until))
+        .map_err(|source| Error::Keys { path, source })?;
+    // printed rather than logged, so it shows up whatever the log level
+    println!("pairing code: {}", code);
+    println!("driver fingerprint: {}", fingerprint(&keys.public));
+    // a driver running as another user, like under sudo, won't see the code
+    println!("saved in {}, for a driver run by this user", dir.display());
+    println!(
+        "enter both in the app within {} minutes. Pair only if the app agrees on the fingerprint",
+        PAIRING_WINDOW.as_secs() / 60
+    );
+    Ok(())
+}
+
+/// The code in the `pairing` file at `path`, as long as it's still good.
+fn open_code(path: &Path) -> Result<String, &'static str> {
+    const OFF: &str = "pairing is off, run `touche-driver pair` on the computer";
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(OFF),
+        Err(e) => {
+            error!("failed to read {}!", path.display());
+            info!("error logs:\n{}", e);
+            return Err("the driver can't read its pairing code");
+        }
+    };
+    let Some((code, until)) = text
+        .trim()
+        .split_once(' ')
+        .and_then(|(code, until)| Some((code, until.parse::<u64>().ok()?)))
+    else {
+        warn!("{} isn't `<code> <until>`, ignoring it", path.display());
+        return Err(OFF);
+    };
+    if now() >= until {
+        info!("the pairing code expired");
+        close_window(path);
+        return Err(OFF);
+    }
+    Ok(code.to_owned())
+}
+
+fn close_window(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path)
+        && e.kind() != ErrorKind::NotFound
+    {
+        error!("failed to remove {}, pairing stays on!", path.display());
+        info!("error logs:\n{}", e);
+    }
+}
+
+/// Seconds since the epoch, which is what the `pairing` file keeps.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// What the user takes from `touche-driver pair` to the phone.
+pub(crate) struct Invitation<'a> {
+    pub(crate) code: &'a str,
+    /// the driver's, as the user was shown it
+    pub(crate) fingerprint: &'a str,
+}
+
+/// The phone's side of the handshake and pairing. With an `invitation` it
+/// pairs, without one the driver has to have the key in `driver_key`, from an
+/// earlier pairing. Returns the label the driver went with and its key.
+pub(crate) fn pair(
+    framed: &mut Framed,
+    keys: &KeyPair,
+    driver_key: Option<&[u8]>,
+    label: &str,
+    invitation: Option<&Invitation>,
+    psk: Option<&str>,
+) -> Result<(String, Vec<u8>), Error> {
+    let psk = psk.map(psk_hash);
+    let mut noise = noise(psk.as_ref())
+        .local_private_key(&keys.private)
+        .build_initiator()
+        .map_err(noise_error)?;
+    send(framed, &mut noise)?;
+    receive(framed, &mut noise)?;
+    let key = noise
+        .get_remote_static()
+        .expect("XX always sends the static key")
+        .to_vec();
+    // a proof is only safe with the real driver, and without an invitation the
+    // driver's key is all there is to go on, so don't even say who we are
+    match invitation {
+        Some(invitation) if !same_fingerprint(&key, invitation.fingerprint) => {
+            return Err(Error::Pairing(format!(
+                "the driver's fingerprint is {}, not the one you were shown. Don't pair with it",
+                fingerprint(&key)
+            )));
+        }
+        None if driver_key != Some(&key[..]) => {
+            return Err(Error::Pairing(match driver_key {
+                Some(_) => "the driver's key changed, pair again if that's expected".to_owned(),
+                None => "never paired with this driver, pair with `touche-driver pair`".to_owned(),
+            }));
+        }
+        _ => {}
+    }
+    let code = invitation.map(|invitation| invitation.code);
+    send(framed, &mut noise)?;
+    let hash = noise.get_handshake_hash().to_vec();
+    framed.encrypt(noise.into_transport_mode().map_err(noise_error)?);
+
+    let request = match code {
+        Some(code) => format!(
+            "P\t{}\t{}",
+            label,
+            hex(&prove(code, "phone", &hash).finalize().into_bytes())
+        ),
+        None => "C".to_owned(),
+    };
+    framed.send(request.as_bytes()).map_err(Error::Network)?;
+
+    let answer = framed
+        .recv_within(PAIRING_TIMEOUT)
+        .map_err(|e| match e.kind() {
+            // the driver only finds out about a different pre-shared key after our last message
+            ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset => Error::Pairing(
+                "the driver hung up. Is the pre-shared key the same on both ends?".to_owned(),
+            ),
+            _ => Error::Network(e),
+        })?;
+    let answer = String::from_utf8_lossy(&answer);
+    let mut fields = answer.split('\t');
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some("OK"), Some(label), Some(proof)) => {
+            if let Some(code) = code {
+                let proof = unhex(proof).unwrap_or_default();
+                if prove(code, "driver", &hash).verify_slice(&proof).is_err() {
+                    return Err(Error::Pairing(
+                        "the driver doesn't know the code, it's not the one you think".to_owned(),
+                    ));
+                }
+            }
+            Ok((label.to_owned(), key))
+        }
+        (Some("NO"), Some(reason), None) => Err(Error::Pairing(reason.to_owned())),
+        _ => Err(Error::Pairing("the driver made no sense".to_owned())),
+    }
+}
+
+/// The Noise handshake both ends do, with `psk` if there is one.
+pub(crate) fn noise(psk: Option<&[u8; 32]>) -> Builder<'_> {
+    let params = match psk {
+        Some(_) => NOISE_PSK,
+        None => NOISE,
+    };
+    let builder =
+        Builder::new(params.parse().expect("the Noise params are valid")).prologue(PROLOGUE);
+    match psk {
+        Some(psk) => builder.psk(3, psk),
+        None => builder,
+    }
+}
+
+fn send(framed: &mut Framed, noise: &mut HandshakeState) -> Result<(), Error> {
+    let mut message = vec![0; MAX_FRAME_LEN];
+    let len = noise
+        .write_message(&[], &mut message)
+        .map_err(noise_error)?;
+    framed.send(&message[..len]).map_err(Error::Network)
+}
+
+fn receive(framed: &mut Framed, noise: &mut HandshakeState) -> Result<(), Error> {
+    let message = framed
+        .recv_within(PAIRING_TIMEOUT)
+        .map_err(Error::Network)?;
+    let mut payload = vec![0; MAX_FRAME_LEN];
+    noise
+        .read_message(&message, &mut payload)
+        .map_err(noise_error)?;
+    Ok(())
+}
+
+fn noise_error(e: snow::Error) -> Error {
+    match e {
+        // with a pre-shared key on one end only, this is where it shows
+        snow::Error::Decrypt => Error::Pairing(
+            "the handshake didn't decrypt. Is the pre-shared key the same on both ends?".to_owned(),
+        ),
+        e => Error::Pairing(format!("the handshake failed: {}", e)),
+    }
+}
+
+fn new_code() -> io::Result<String> {
+    let mut bytes = [0; 4];
+    getrandom::fill(&mut bytes)?;
+    Ok(format!("{:06}", u32::from_le_bytes(bytes) % 1_000_000))
+}
+
+/// The start of the key's hash, short enough for a person to compare,
+/// like `1a2b-3c4d-5e6f-7a8b-9c0d`.
+pub(crate) fn fingerprint(key: &[u8]) -> String {
+    let hash = hex(&Sha256::digest(key)[..FINGERPRINT_LEN]);
+    let groups: Vec<&str> = (0..hash.len())
+        .step_by(4)
+        .map(|i| &hash[i..i + 4])
+        .collect();
+    groups.join("-")
+}
+
+/// Whether `typed` is the fingerprint of `key`, however the user wrote it down.
+fn same_fingerprint(key: &[u8], typed: &str) -> bool {
+    let typed: String = typed
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    typed == fingerprint(key).replace('-', "")
+}
+
+/// Noise wants exactly 32 bytes, whatever the user typed.
+fn psk_hash(psk: &str) -> [u8; 32] {
+    Sha256::digest(psk.as_bytes()).into()
+}
+
+/// Proves knowing `code` on the connection with handshake hash `hash`,
+/// as either `side`, so one side's proof can't be sent back as the other's.
+fn prove(code: &str, side: &str, hash: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(code.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(side.as_bytes());
+    mac.update(hash);
+    mac
+}
+
+fn is_valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= MAX_LABEL_LEN
+        && label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        io::Read,
+        net::TcpListener,
+        process,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::*;
+
+    const PHONE: &[u8] = &[1; 32];
+    const OTHER_PHONE: &[u8] = &[2; 32];
+    const HASH: &[u8] = &[3; 32];
+    const OFF: Result<(String, String), &str> =
+        Err("pairing is off, run `touche-driver pair` on the computer");
+
+    /// A driver with keys of its own, in a directory no other test uses.
+    fn pairing() -> Pairing {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "touche-pairing-{}-{}",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        Pairing::new(&dir, None).unwrap()
+    }
+
+    /// What `touche-driver pair` does, with a code of our choosing.
+    fn open(pairing: &Pairing, code: &str) {
+        fs::write(&pairing.window, format!("{} {}\n", code, now() + 60)).unwrap();
+    }
+
+    /// Our end of a connection, and the phone's.
+    fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ours = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (ours, listener.accept().unwrap().0)
+    }
+
+    fn check(
+        pairing: &Pairing,
+        key: &[u8],
+        request: &str,
+    ) -> Result<(String, String), &'static str> {
+        pairing.check(key, HASH, request.as_bytes(), connection().0)
+    }
+
+    fn request(label: &str, code: &str, side: &str) -> String {
+        let proof = prove(code, side, HASH).finalize().into_bytes();
+        format!("P\t{}\t{}", label, hex(&proof))
+    }
+
+    #[test]
+    fn pairs_with_the_right_code() {
+        let pairing = pairing();
+        open(&pairing, "123456");
+
+        let (label, answer) = check(&pairing, PHONE, &request("pixel", "123456", "phone")).unwrap();
+
+        assert_eq!(label, "pixel");
+        let answer = unhex(&answer).unwrap();
+        assert!(
+            prove("123456", "driver", HASH)
+                .verify_slice(&answer)
+                .is_ok()
+        );
+        assert_eq!(
+            pairing.trusted.name_of(PHONE).unwrap().as_deref(),
+            Some("pixel")
+        );
+        // one time means one time
+        assert!(!pairing.window.exists());
+    }
+
+    #[test]
+    fn refuses_a_wrong_code() {
+        let pairing = pairing();
+        open(&pairing, "123456");
+
+        let result = check(&pairing, PHONE, &request("pixel", "654321", "phone"));
+
+        assert_eq!(result, Err("wrong code"));
+        assert_eq!(pairing.trusted.name_of(PHONE).unwrap(), None);
+        assert!(pairing.window.exists());
+    }
+
+    #[test]
+    fn refuses_the_drivers_proof_from_a_phone() {
+        let pairing = pairing();
+        open(&pairing, "123456");
+
+        let result = check(&pairing, PHONE, &request("pixel", "123456", "driver"));
+
+        assert_eq!(result, Err("wrong code"));
+    }
+
+    #[test]
+    fn answers_with_a_proof_the_phone_did_not_send() {
+        let pairing = pairing();
+        open(&pairing, "123456");
+        let request = request("pixel", "123456", "phone");
+
+        let (_, answer) = check(&pairing, PHONE, &request).unwrap();
+
+        // otherwise a fake driver could just send the phone's proof back
+        assert!(!request.ends_with(&answer));
+    }
+
+    #[test]
+    fn too_many_wrong_codes_turn_pairing_off() {
+        let pairing = pairing();
+        open(&pairing, "123456");
+        for _ in 0..MAX_CODE_ATTEMPTS {
+            let result = check(&pairing, PHONE, &request("pixel", "000000", "phone"));
+            assert_eq!(result, Err("wrong code"));
+        }
+
+        assert_eq!(
+            check(&pairing, PHONE, &request("pixel", "123456", "phone")),
+            OFF
+        );
+
+        // until the user asks for a new code
+        open(&pairing, "234567");
+        assert!(check(&pairing, PHONE, &request("pixel", "234567", "phone")).is_ok());
+    }
+
+    #[test]
+    fn a_new_code_starts_counting_over() {
+        let pairing = pairing();
+        open(&pairing, "123456");
+        for _ in 1..MAX_CODE_ATTEMPTS {
+            let _ = check(&pairing, PHONE, &request("pixel", "000000", "phone"));
+        }
+
+        open(&pairing, "234567");
+        let _ = check(&pairing, PHONE, &request("pixel", "000000", "phone"));
+
+        assert!(check(&pairing, PHONE, &request("pixel", "234567", "phone")).is_ok());
+    }
+
+    #[test]
+    fn refuses_to_pair_without_a_window() {
+        let pairing = pairing();
+
+        assert_eq!(
+            check(&pairing, PHONE, &request("pixel", "123456", "phone")),
+            OFF
+        );
+    }
+
+    #[test]
+    fn refuses_an_expired_code() {
+        let pairing = pairing();
+        fs::write(&pairing.window, format!("123456 {}\n", now() - 1)).unwrap();
+
+        assert_eq!(
+            check(&pairing, PHONE, &request("pixel", "123456", "phone")),
+            OFF
+        );
+        assert!(!pairing.window.exists());
+    }
+
+    #[test]
+    fn refuses_a_name_that_is_taken() {
+        let pairing = pairing();
+        open(&pairing, "123456");
+        check(&pairing, PHONE, &request("pixel", "123456", "phone")).unwrap();
+        open(&pairing, "234567");
+
+        let result = check(&pairing, OTHER_PHONE, &request("pixel", "234567", "phone"));
+
+        assert_eq!(result, Err("another phone goes by that name"));
+        // the code is still good for another name
+        assert!(
+            check(
+                &pairing,
+                OTHER_PHONE,
+                &request("pixel-2", "234567", "phone")
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn lets_a_trusted_phone_come_back() {
+        let pairing = pairing();
+        pairing.trusted.trust("pixel", PHONE).unwrap();
+
+        assert_eq!(
+            check(&pairing, PHONE, "C"),
+            Ok(("pixel".to_owned(), String::new()))
+        );
+        assert_eq!(
+            check(&pairing, OTHER_PHONE, "C"),
+            Err("unknown phone, pair it with the code")
+        );
+    }
+
+    #[test]
+    fn hangs_up_on_the_old_connection_of_a_phone_that_comes_back() {
+        let pairing = pairing();
+        pairing.trusted.trust("pixel", PHONE).unwrap();
+        let (ours, mut phone) = connection();
+        pairing.check(PHONE, HASH, b"C", ours).unwrap();
+
+        thread::scope(|scope| {
+            // the old session, which ends once its connection is hung up on
+            scope.spawn(|| {
+                assert_eq!(phone.read(&mut [0; 16]).unwrap(), 0);
+                drop(Paired {
+                    pairing: &pairing,
+                    label: "pixel".to_owned(),
+                });
+            });
+            assert!(check(&pairing, PHONE, "C").is_ok());
+        });
+    }
+
+    #[test]
+    fn refuses_a_phone_whose_old_connection_hangs_on() {
+        let pairing = pairing();
+        pairing.trusted.trust("pixel", PHONE).unwrap();
+        check(&pairing, PHONE, "C").unwrap();
+
+        assert_eq!(check(&pairing, PHONE, "C"), Err("already connected"));
+    }
+
+    #[test]
+    fn fingerprints_compare_however_they_are_written() {
+        let fingerprint = fingerprint(PHONE);
+
+        assert!(same_fingerprint(PHONE, &fingerprint));
+        assert!(same_fingerprint(
+            PHONE,
+            &fingerprint.to_uppercase().replace('-', " ")
+        ));
+        assert!(!same_fingerprint(OTHER_PHONE, &fingerprint));
+    }
+}
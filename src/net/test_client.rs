@@ -0,0 +1,133 @@
+use std::{f32::consts::TAU, fmt::Write, net::TcpStream, thread, time::Duration};
+
+use log::info;
+
+use crate::{
+    cli::TestClientArgs,
+    data::capabilities::{FEATURE_PRESSURE, FEATURE_STYLUS},
+    driver::{OPCODE_CAPABILITIES, OPCODE_HANDSHAKE, OPCODE_START_TEXT},
+    error::Error,
+};
+
+use super::{
+    Framed, PAIRING_TIMEOUT,
+    keys::{KeyPair, TrustedKeys},
+    pairing::{Invitation, pair},
+};
+
+const WIDTH: i32 = 1080;
+const HEIGHT: i32 = 2400;
+const FRAME_RATE: u16 = 60;
+
+/// `touche-driver test-client`: plays a phone over TCP. It pairs, answers
+/// the handshake and then draws a circle with the pen, followed by a
+/// two-finger swipe, so both devices get something to do.
+///
+/// Like a phone, it keeps a key pair of its own and remembers the key of
+/// every driver it paired with, by address.
+pub(crate) fn test_client(args: &TestClientArgs) -> Result<(), Error> {
+    let keys = KeyPair::load_or_create(&args.keys.join("identity"))?;
+    let drivers = TrustedKeys::new(args.keys.join("trusted_drivers"));
+    let name = args.addr.to_string();
+    let driver_key = drivers.key_of(&name)?;
+
+    let stream = TcpStream::connect(args.addr).map_err(Error::Network)?;
+    let mut framed = Framed::new(stream).map_err(Error::Network)?;
+    let invitation = args
+        .code
+        .as_deref()
+        .zip(args.fingerprint.as_deref())
+        .map(|(code, fingerprint)| Invitation { code, fingerprint });
+    let (label, driver_key) = pair(
+        &mut framed,
+        &keys,
+        driver_key.as_deref(),
+        &args.label,
+        invitation.as_ref(),
+        args.psk.as_deref(),
+    )?;
+    if args.code.is_some() {
+        // pairing again means whatever key the driver had before is out
+        drivers.forget(&name)?;
+        if !drivers.trust(&name, &driver_key)? {
+            return Err(Error::Pairing(format!(
+                "{} is already trusted with another key",
+                name
+            )));
+        }
+        println!("paired as {}, come back without --code", label);
+    } else {
+        println!("connected as {}", label);
+    }
+
+    let request = framed
+        .recv_within(PAIRING_TIMEOUT)
+        .map_err(Error::Network)?;
+    if request.first() != Some(&OPCODE_HANDSHAKE) {
+        return Err(Error::Pairing("expected a handshake".to_owned()));
+    }
+    // protocol version 0, so the driver sticks to text rows
+    let reply = format!(
+        "X\t{}\t{}\nC\t0\t{}\t2\t{}\n",
+        WIDTH,
+        HEIGHT,
+        FEATURE_STYLUS | FEATURE_PRESSURE,
+        FRAME_RATE
+    );
+    framed.send(reply.as_bytes()).map_err(Error::Network)?;
+
+    // we sent a `C` row, so the driver tells us what it can do
+    let capabilities = framed
+        .recv_within(PAIRING_TIMEOUT)
+        .map_err(Error::Network)?;
+    if capabilities.first() != Some(&OPCODE_CAPABILITIES) {
+        return Err(Error::Pairing(
+            "expected the driver's capabilities".to_owned(),
+        ));
+    }
+
+    let start = framed
+        .recv_within(PAIRING_TIMEOUT)
+        .map_err(Error::Network)?;
+    if start.first() != Some(&OPCODE_START_TEXT) {
+        return Err(Error::Pairing(
+            "expected to be asked for text rows".to_owned(),
+        ));
+    }
+
+    let frames = u32::try_from(args.seconds * u64::from(FRAME_RATE)).unwrap_or(u32::MAX);
+    info!("streaming {} frames", frames);
+    for frame in 0..frames {
+        let progress = frame as f32 / frames as f32;
+        framed
+            .send(rows(progress).as_bytes())
+            .map_err(Error::Network)?;
+        thread::sleep(Duration::from_secs(1) / u32::from(FRAME_RATE));
+    }
+
+    // lift everything before going, like a well-behaved phone
+    let (cx, cy) = (WIDTH as f32 / 2.0, HEIGHT as f32 / 2.0);
+    let goodbye = format!("S\t{cx}\t{cy}\t0\nF\t{cx}\t{cy}\t0\t0\nF\t{cx}\t{cy}\t0\t1\n");
+    framed.send(goodbye.as_bytes()).map_err(Error::Network)?;
+    println!("done");
+    Ok(())
+}
+
+/// The pen goes around a circle in the first half, two fingers swipe down in the second.
+fn rows(progress: f32) -> String {
+    let (cx, cy) = (WIDTH as f32 / 2.0, HEIGHT as f32 / 2.0);
+    let mut rows = String::new();
+    if progress < 0.5 {
+        let angle = progress * 2.0 * TAU;
+        let radius = WIDTH as f32 / 3.0;
+        let (x, y) = (cx + radius * angle.cos(), cy + radius * angle.sin());
+        let pressure = 0.5 + 0.4 * (angle * 3.0).sin();
+        let _ = writeln!(rows, "S\t{:.1}\t{:.1}\t1\t{:.3}", x, y, pressure);
+    } else {
+        let y = HEIGHT as f32 * (0.25 + (progress - 0.5));
+        for (touch_id, x) in [(0, cx - 150.0), (1, cx + 150.0)] {
+            let _ = writeln!(rows, "F\t{:.1}\t{:.1}\t1\t{}", x, y, touch_id);
+        }
+    }
+    rows
+}
@@ -0,0 +1,159 @@
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::Path,
+};
+
+use nusb::list_devices;
+
+#[cfg(unix)]
+use super::group_name;
+use super::{DEFAULT_RULES_PATH, UINPUT_PATH, looks_like_phone, process_ids};
+
+/// Outcome of every check, printed as it goes.
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn ok(&mut self, message: &str) {
+        println!("[ ok ] {}", message);
+    }
+
+    fn warn(&mut self, message: &str, hint: &str) {
+        println!("[warn] {}", message);
+        println!("       {}", hint);
+    }
+
+    fn fail(&mut self, message: &str, hint: &str) {
+        self.failures += 1;
+        println!("[FAIL] {}", message);
+        println!("       {}", hint);
+    }
+}
+
+/// `touche-driver doctor`: checks everything the driver needs from the system
+/// and explains how to fix what's missing. Returns whether all checks passed.
+pub(crate) fn doctor() -> bool {
+    let mut report = Report { failures: 0 };
+    let (uid, groups) = process_ids().unwrap_or_default();
+    if uid == 0 {
+        report.ok("running as root, permissions won't get in the way");
+    }
+
+    check_uinput(&mut report, uid, &groups);
+    check_rules(&mut report);
+    check_usb(&mut report);
+
+    if report.failures == 0 {
+        println!("all good!");
+    } else {
+        println!("{} problem(s) found", report.failures);
+    }
+    report.failures == 0
+}
+
+fn check_uinput(report: &mut Report, uid: u32, groups: &[u32]) {
+    let metadata = match fs::metadata(UINPUT_PATH) {
+        Ok(metadata) => metadata,
+        Err(_) => {
+            report.fail(
+                &format!("{} is missing", UINPUT_PATH),
+                "load the module with `sudo modprobe uinput`, \
+                 and add `uinput` to /etc/modules-load.d/ to keep it loaded",
+            );
+            return;
+        }
+    };
+    report.ok(&format!("{} exists", UINPUT_PATH));
+    #[cfg(unix)]
+    check_group(report, &metadata, uid, groups);
+    #[cfg(not(unix))]
+    let _ = (metadata, uid, groups);
+
+    match OpenOptions::new().write(true).open(UINPUT_PATH) {
+        Ok(_) => report.ok(&format!("{} is writable", UINPUT_PATH)),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => report.fail(
+            &format!("{} is not writable", UINPUT_PATH),
+            "install the rules from `touche-driver udev`, join the group owning it, \
+             or run as root",
+        ),
+        Err(e) => report.fail(
+            &format!("can't open {}: {}", UINPUT_PATH, e),
+            "check that the uinput module works with `sudo modprobe uinput`",
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn check_group(report: &mut Report, metadata: &fs::Metadata, uid: u32, groups: &[u32]) {
+    use std::os::unix::fs::MetadataExt;
+
+    let group = group_name(metadata.gid()).unwrap_or_else(|| metadata.gid().to_string());
+    if uid != 0 && metadata.gid() != 0 && !groups.contains(&metadata.gid()) {
+        report.warn(
+            &format!(
+                "you are not in the `{}` group owning {}",
+                group, UINPUT_PATH
+            ),
+            &format!(
+                "add yourself with `sudo usermod -aG {} $USER` and log in again",
+                group
+            ),
+        );
+    }
+}
+
+fn check_rules(report: &mut Report) {
+    if Path::new(DEFAULT_RULES_PATH).exists() {
+        report.ok(&format!("udev rules installed at {}", DEFAULT_RULES_PATH));
+    } else {
+        report.warn(
+            &format!("no udev rules at {}", DEFAULT_RULES_PATH),
+            "plug your phone in and run `sudo touche-driver udev`, \
+             unless your distro's android-udev-rules already cover it",
+        );
+    }
+}
+
+fn check_usb(report: &mut Report) {
+    let devices = match list_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            report.fail(
+                &format!("can't list USB devices: {}", e),
+                "check that /sys/bus/usb is mounted and readable",
+            );
+            return;
+        }
+    };
+
+    let mut phones = 0;
+    for device_info in devices.filter(looks_like_phone) {
+        phones += 1;
+        let name = format!(
+            "{} ({:04x}:{:04x})",
+            device_info.product_string().unwrap_or("unknown phone"),
+            device_info.vendor_id(),
+            device_info.product_id()
+        );
+        match device_info.open() {
+            Ok(_) => report.ok(&format!("{} can be opened", name)),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => report.fail(
+                &format!("no permission to open {}", name),
+                "install udev rules with `sudo touche-driver udev` and replug the phone, \
+                 or run as root",
+            ),
+            Err(e) => report.fail(
+                &format!("can't open {}: {}", name, e),
+                "replug the phone, or try another cable or USB port",
+            ),
+        }
+    }
+    if phones == 0 {
+        report.warn(
+            "no phone found",
+            "plug it in, unlock it and enable USB debugging, then run this again",
+        );
+    }
+}
@@ -0,0 +1,63 @@
+pub(crate) mod doctor;
+pub(crate) mod udev;
+
+use std::fs;
+
+use nusb::DeviceInfo;
+
+use crate::{adb::has_adb_interface, aoa::utils::is_aoa};
+
+/// Where the driver creates its virtual devices.
+const UINPUT_PATH: &str = "/dev/uinput";
+/// Where `touche-driver udev` puts its rules unless told otherwise.
+pub(crate) const DEFAULT_RULES_PATH: &str = "/etc/udev/rules.d/70-touche.rules";
+
+const USB_CLASS_IMAGING: u8 = 0x06;
+const USB_CLASS_VENDOR: u8 = 0xff;
+
+/// Whether a USB device is worth writing rules for, or switching to accessory mode.
+///
+/// There is no way to tell a phone from any other gadget for sure, so this
+/// looks for the interfaces Android exposes: ADB, MTP/PTP or an accessory.
+/// MTP is a vendor-specific interface on most phones, only its name gives it away.
+pub(crate) fn looks_like_phone(device_info: &DeviceInfo) -> bool {
+    is_aoa(device_info)
+        || has_adb_interface(device_info)
+        || device_info.interfaces().any(|interface| {
+            interface.class() == USB_CLASS_IMAGING
+                || (interface.class() == USB_CLASS_VENDOR
+                    && interface.interface_string() == Some("MTP"))
+        })
+}
+
+/// Name of the group with this id, from `/etc/group`.
+fn group_name(gid: u32) -> Option<String> {
+    let groups = fs::read_to_string("/etc/group").ok()?;
+    groups.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let id = fields.nth(1)?.parse::<u32>().ok()?;
+        (id == gid).then(|| name.to_owned())
+    })
+}
+
+/// Effective user id and supplementary groups of this process,
+/// from `/proc/self/status`.
+fn process_ids() -> Option<(u32, Vec<u32>)> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(|rest| {
+                rest.split_whitespace()
+                    .filter_map(|id| id.parse::<u32>().ok())
+                    .collect::<Vec<u32>>()
+            })
+    };
+    // `Uid:` lists the real, effective, saved and filesystem ids
+    let uid = *field("Uid:")?.get(1)?;
+    let mut groups = field("Groups:")?;
+    groups.extend(field("Gid:")?.get(1));
+    Some((uid, groups))
+}
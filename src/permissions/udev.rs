@@ -0,0 +1,86 @@
+use std::{collections::BTreeSet, fmt::Write, fs, io, path::Path};
+
+use nusb::list_devices;
+
+use crate::aoa::utils::is_aoa;
+
+use super::looks_like_phone;
+
+/// Vendor id phones switch to once they are in accessory mode.
+const GOOGLE_VENDOR_ID: u16 = 0x18d1;
+
+/// Vendor ids and names of whatever looks like a phone right now,
+/// so the phone has to be connected (and unlocked, for some vendors).
+fn phone_vendors() -> io::Result<BTreeSet<(u16, String)>> {
+    Ok(list_devices()?
+        .filter(looks_like_phone)
+        // the accessory rule covers these, Pixels before the switch still need their own
+        .filter(|device_info| !is_aoa(device_info))
+        .map(|device_info| {
+            let name = device_info
+                .manufacturer_string()
+                .or(device_info.product_string())
+                .unwrap_or("unknown vendor")
+                .to_owned();
+            (device_info.vendor_id(), name)
+        })
+        .collect())
+}
+
+/// Builds a rules file for the given phone vendors.
+fn rules(vendors: &BTreeSet<(u16, String)>) -> String {
+    let mut rules = String::new();
+    // writing to a String can't fail
+    let _ = writeln!(rules, "# generated by `touche-driver udev`");
+    let _ = writeln!(rules);
+    let _ = writeln!(rules, "# phones in accessory mode");
+    let _ = writeln!(
+        rules,
+        "SUBSYSTEM==\"usb\", ATTR{{idVendor}}==\"{:04x}\", ATTR{{idProduct}}==\"2d0[0-5]\", MODE=\"0660\", TAG+=\"uaccess\"",
+        GOOGLE_VENDOR_ID
+    );
+    let _ = writeln!(rules);
+    let _ = writeln!(rules, "# phones before they are switched to accessory mode");
+    for (vendor_id, name) in vendors {
+        let _ = writeln!(rules, "# {}", name);
+        let _ = writeln!(
+            rules,
+            "SUBSYSTEM==\"usb\", ATTR{{idVendor}}==\"{:04x}\", MODE=\"0660\", TAG+=\"uaccess\"",
+            vendor_id
+        );
+    }
+    let _ = writeln!(rules);
+    let _ = writeln!(rules, "# virtual tablet and touchpad");
+    let _ = writeln!(
+        rules,
+        "KERNEL==\"uinput\", SUBSYSTEM==\"misc\", MODE=\"0660\", GROUP=\"input\", TAG+=\"uaccess\", OPTIONS+=\"static_node=uinput\""
+    );
+    rules
+}
+
+/// `touche-driver udev [PATH]`: writes the rules to `path`, or prints them for `-`.
+pub(crate) fn udev(path: &str) -> io::Result<()> {
+    let vendors = phone_vendors()?;
+    if vendors.is_empty() {
+        eprintln!("no phone found, the rules only cover phones already in accessory mode");
+    }
+    let rules = rules(&vendors);
+    if path == "-" {
+        print!("{}", rules);
+        return Ok(());
+    }
+
+    if let Err(e) = fs::write(Path::new(path), &rules) {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            println!(
+                "can't write {}, run this as root or save the rules with `touche-driver udev -`",
+                path
+            );
+        }
+        return Err(e);
+    }
+    println!("wrote {}", path);
+    println!("reload them with `sudo udevadm control --reload-rules && sudo udevadm trigger`,");
+    println!("then replug the phone");
+    Ok(())
+}
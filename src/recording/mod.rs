@@ -0,0 +1,275 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use chrono::Local;
+use log::{error, info, warn};
+
+use crate::{data::decoder::DEFAULT_MAX_PENDING, error::Error, source::ToucheSource};
+
+/// Start of every recording, followed by [`FORMAT_VERSION`].
+const MAGIC: &[u8; 8] = b"TOUCHREC";
+const FORMAT_VERSION: u8 = 1;
+/// microseconds since the recording started (u64 LE), then the buffer length (u32 LE)
+const RECORD_HEADER_LEN: usize = 12;
+
+/// Tees raw buffers from a phone into a session file.
+///
+/// The file is the magic and version, then one record per buffer:
+/// when it arrived, its length and the bytes themselves. The first buffer is
+/// the phone's answer to the handshake, the rest is the stream.
+pub(crate) struct Recorder {
+    path: PathBuf,
+    /// `None` once writing failed, the session goes on without recording
+    file: Option<BufWriter<File>>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Starts `<dir>/<label>-<timestamp>.touche`. Milliseconds are part of the
+    /// timestamp, retries of a session come quicker than once a second.
+    pub(crate) fn create(dir: &Path, label: &str) -> Result<Recorder, Error> {
+        let path = dir.join(format!(
+            "{}-{}.touche",
+            label,
+            Local::now().format("%Y%m%d-%H%M%S-%3f")
+        ));
+        let io_error = |source| Error::Recording {
+            path: path.clone(),
+            source,
+        };
+        fs::create_dir_all(dir).map_err(io_error)?;
+        let mut file = BufWriter::new(File::create(&path).map_err(io_error)?);
+        file.write_all(MAGIC).map_err(io_error)?;
+        file.write_all(&[FORMAT_VERSION]).map_err(io_error)?;
+        info!("recording to {}", path.display());
+        Ok(Recorder {
+            path,
+            file: Some(file),
+            start: Instant::now(),
+        })
+    }
+
+    pub(crate) fn record(&mut self, data: &[u8]) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let elapsed = self.start.elapsed().as_micros() as u64;
+        // flushed right away, so a killed driver still leaves a usable file
+        let result = file
+            .write_all(&elapsed.to_le_bytes())
+            .and_then(|_| file.write_all(&(data.len() as u32).to_le_bytes()))
+            .and_then(|_| file.write_all(data))
+            .and_then(|_| file.flush());
+        if let Err(e) = result {
+            error!(
+                "failed to write {}, recording stopped!",
+                self.path.display()
+            );
+            info!("error logs:\n{}", e);
+            self.file = None;
+        }
+    }
+}
+
+/// A source that tees everything the phone sends into a [`Recorder`].
+pub(crate) struct RecordedSource<'a> {
+    pub(crate) source: &'a mut dyn ToucheSource,
+    pub(crate) recorder: Recorder,
+}
+
+impl ToucheSource for RecordedSource<'_> {
+    fn handshake(&mut self, request: &[u8]) -> Result<Vec<u8>, Error> {
+        let reply = self.source.handshake(request)?;
+        self.recorder.record(&reply);
+        Ok(reply)
+    }
+
+    fn read_frame(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+        let frame = self.source.read_frame(timeout)?;
+        if let Some(frame) = &frame {
+            self.recorder.record(frame);
+        }
+        Ok(frame)
+    }
+
+    fn send_opcode(&mut self, opcode: &[u8]) -> Result<(), Error> {
+        self.source.send_opcode(opcode)
+    }
+}
+
+/// A session file opened for replay.
+pub(crate) struct Recording {
+    path: PathBuf,
+    reader: BufReader<File>,
+}
+
+impl Recording {
+    pub(crate) fn open(path: &Path) -> Result<Recording, Error> {
+        let io_error = |source| Error::Recording {
+            path: path.to_owned(),
+            source,
+        };
+        let mut reader = BufReader::new(File::open(path).map_err(io_error)?);
+        let mut header = [0; MAGIC.len() + 1];
+        reader.read_exact(&mut header).map_err(io_error)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(io_error(invalid_data("not a touche recording")));
+        }
+        if header[MAGIC.len()] != FORMAT_VERSION {
+            return Err(io_error(invalid_data(
+                "recorded by an incompatible version",
+            )));
+        }
+        Ok(Recording {
+            path: path.to_owned(),
+            reader,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The next buffer and when it arrived, counted from the start of the recording.
+    /// A record cut short, as left behind by a driver that got killed, ends the recording.
+    pub(crate) fn next_buffer(&mut self) -> Result<Option<(Duration, Vec<u8>)>, Error> {
+        let mut header = [0; RECORD_HEADER_LEN];
+        match self.read_exact(&mut header) {
+            Ok(true) => {}
+            Ok(false) => return Ok(None),
+            Err(source) => {
+                return Err(Error::Recording {
+                    path: self.path.clone(),
+                    source,
+                });
+            }
+        }
+        let elapsed = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+        // no source hands out buffers this big, it's a broken file
+        // and not worth allocating for
+        if len > DEFAULT_MAX_PENDING {
+            return Err(Error::Recording {
+                path: self.path.clone(),
+                source: invalid_data(&format!("{} byte record, the file is broken", len)),
+            });
+        }
+        let mut data = vec![0; len];
+        match self.read_exact(&mut data) {
+            Ok(true) => Ok(Some((Duration::from_micros(elapsed), data))),
+            Ok(false) => {
+                warn!("the last record of {} is cut short", self.path.display());
+                Ok(None)
+            }
+            Err(source) => Err(Error::Recording {
+                path: self.path.clone(),
+                source,
+            }),
+        }
+    }
+
+    /// `Ok(false)` when the file ends before `buf` is full.
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs::OpenOptions, process};
+
+    use super::*;
+
+    /// A fresh directory for one test, so tests running side by side
+    /// don't read each other's recordings.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("touche-recording-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record(dir: &Path, buffers: &[&[u8]]) -> PathBuf {
+        let mut recorder = Recorder::create(dir, "R58M123ABC").unwrap();
+        for buffer in buffers {
+            recorder.record(buffer);
+        }
+        recorder.path
+    }
+
+    #[test]
+    fn reads_back_what_was_recorded() {
+        let dir = scratch("round-trip");
+        let path = record(&dir, &[b"X\t1080\t2400\n", b"", b"S\t1\t2\t1\n"]);
+
+        let mut recording = Recording::open(&path).unwrap();
+        let mut buffers = vec![];
+        let mut last = Duration::ZERO;
+        while let Some((at, buffer)) = recording.next_buffer().unwrap() {
+            assert!(at >= last);
+            last = at;
+            buffers.push(buffer);
+        }
+
+        assert_eq!(buffers, [&b"X\t1080\t2400\n"[..], b"", b"S\t1\t2\t1\n"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_record_cut_short_ends_the_recording() {
+        let dir = scratch("cut-short");
+        let path = record(&dir, &[b"X\t1080\t2400\n", b"S\t1\t2\t1\n"]);
+        let len = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let mut recording = Recording::open(&path).unwrap();
+
+        assert!(recording.next_buffer().unwrap().is_some());
+        assert!(recording.next_buffer().unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_records_over_the_limit() {
+        let dir = scratch("oversized");
+        let path = record(&dir, &[]);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&0u64.to_le_bytes()).unwrap();
+        file.write_all(&(DEFAULT_MAX_PENDING as u32 + 1).to_le_bytes())
+            .unwrap();
+
+        let mut recording = Recording::open(&path).unwrap();
+
+        assert!(matches!(
+            recording.next_buffer(),
+            Err(Error::Recording { source, .. }) if source.kind() == ErrorKind::InvalidData
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_files_that_are_no_recording() {
+        let dir = scratch("not-a-recording");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "[defaults]\nrotation = 90\n").unwrap();
+
+        assert!(matches!(
+            Recording::open(&path),
+            Err(Error::Recording { source, .. }) if source.kind() == ErrorKind::InvalidData
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
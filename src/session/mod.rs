@@ -0,0 +1,415 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{Level, error, info, log, warn};
+use nusb::{DeviceId, DeviceInfo};
+
+use crate::{
+    adb::Forward,
+    aoa::AoaDevice,
+    config::{
+        Adb,
+        live::{LiveConfig, LiveOptions},
+    },
+    driver::{Devices, driver_loop},
+    error::Error,
+    recording::{RecordedSource, Recorder},
+    source::ToucheSource,
+};
+
+/// How many times a session tries to get a phone going before giving up,
+/// unless it keeps trying for as long as the phone is plugged in.
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the second attempt, doubled for every one after it.
+const FIRST_BACKOFF: Duration = Duration::from_millis(250);
+/// The longest wait between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// How long devices of a disconnected phone wait for it to come back.
+const PARK_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often parked devices get checked for having waited long enough.
+const REAP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Devices of disconnected phones, keyed by session label.
+type ParkingLot = Arc<Mutex<HashMap<String, Parked>>>;
+/// Opens a new connection to a phone, once per attempt.
+type Connect = Box<dyn FnMut() -> Result<Box<dyn ToucheSource>, Error> + Send>;
+
+struct Parked {
+    devices: Devices,
+    since: Instant,
+}
+
+/// Runs one driver session per connected phone, each on its own thread,
+/// so the hotplug loop stays free to pick up more of them.
+pub(crate) struct SessionManager {
+    sessions: HashMap<DeviceId, Session>,
+    parked: ParkingLot,
+    config: Arc<LiveConfig>,
+    /// where sessions save what their phone sends, when recording
+    record: Option<Arc<Path>>,
+}
+
+struct Session {
+    label: String,
+    handle: JoinHandle<()>,
+    /// set once the phone is unplugged, so the session stops retrying
+    unplugged: Arc<AtomicBool>,
+}
+
+/// What sessions of phones that came over the network share with the USB ones:
+/// the config, parked devices and whether to record.
+#[derive(Clone)]
+pub(crate) struct RemoteSessions {
+    config: Arc<LiveConfig>,
+    parked: ParkingLot,
+    record: Option<Arc<Path>>,
+}
+
+impl RemoteSessions {
+    /// Drives a phone on the calling thread until it goes away. There are no
+    /// retries, a phone that drops off comes back on a new connection.
+    pub(crate) fn run(&self, source: &mut dyn ToucheSource, label: &str) {
+        let mut options = LiveOptions::new(Arc::clone(&self.config), label);
+        let mut devices = unpark(&self.parked, label);
+        match drive(
+            source,
+            self.record.as_deref(),
+            &mut options,
+            label,
+            &mut devices,
+        ) {
+            Ok(_) => info!("{} went away", label),
+            Err(e) => {
+                error!("{} failed: {}", label, e);
+                info!("error logs:\n{:?}", e);
+            }
+        }
+        park(&self.parked, label, devices);
+        info!("session {} finished", label);
+    }
+}
+
+impl SessionManager {
+    pub(crate) fn new(config: Arc<LiveConfig>, record: Option<PathBuf>) -> SessionManager {
+        let parked: ParkingLot = Arc::new(Mutex::new(HashMap::new()));
+        // phones on the network come and go without any USB events,
+        // so parked devices can't wait for one to get cleaned up
+        let reaper = Arc::clone(&parked);
+        let spawned = thread::Builder::new()
+            .name("touche-reaper".to_owned())
+            .spawn(move || {
+                loop {
+                    thread::sleep(REAP_INTERVAL);
+                    reap_parked(&reaper);
+                }
+            });
+        if let Err(e) = spawned {
+            error!("failed to spawn the thread that cleans up parked devices!");
+            info!("error logs:\n{}", e);
+        }
+        SessionManager {
+            sessions: HashMap::new(),
+            parked,
+            config,
+            record: record.map(Arc::from),
+        }
+    }
+
+    /// A handle for running sessions of phones that don't come over USB.
+    pub(crate) fn remote(&self) -> RemoteSessions {
+        RemoteSessions {
+            config: Arc::clone(&self.config),
+            parked: Arc::clone(&self.parked),
+            record: self.record.clone(),
+        }
+    }
+
+    /// Starts a session for a phone that is already in accessory mode.
+    pub(crate) fn start(&mut self, device_info: DeviceInfo) {
+        if self.is_running(device_info.id()) {
+            return;
+        }
+        let label = session_label(&device_info);
+        self.spawn(
+            device_info.id(),
+            label,
+            false,
+            Box::new(move || Ok(Box::new(AoaDevice::new(device_info.clone())?))),
+        );
+    }
+
+    /// Starts a session for a phone that can't do accessory mode,
+    /// through `adb forward` to the touche app. The app may not be running
+    /// yet, or get closed and opened again, so this one keeps trying until
+    /// the phone is unplugged.
+    pub(crate) fn start_adb(&mut self, device_info: &DeviceInfo, settings: &Adb) {
+        if self.is_running(device_info.id()) {
+            return;
+        }
+        let Some(serial) = device_info.serial_number().filter(|it| !it.is_empty()) else {
+            warn!("the phone has no serial number, adb can't tell it apart");
+            return;
+        };
+        let settings = settings.clone();
+        let label = serial.to_owned();
+        let serial = serial.to_owned();
+        // adb can take a while, so the forward is set up on the session
+        // thread, and again on the next attempt if it failed
+        let mut forward = None;
+        self.spawn(
+            device_info.id(),
+            label,
+            true,
+            Box::new(move || {
+                let forward = match &mut forward {
+                    Some(forward) => forward,
+                    None => forward.insert(Forward::new(&settings, &serial)?),
+                };
+                Ok(Box::new(forward.connect()?))
+            }),
+        );
+    }
+
+    fn is_running(&mut self, id: DeviceId) -> bool {
+        self.reap();
+        let running = self.sessions.contains_key(&id);
+        if running {
+            warn!("a session for this device is already running");
+        }
+        running
+    }
+
+    /// Runs `connect` on a thread of its own. With `keep_trying` the session
+    /// retries until the phone is unplugged instead of giving up at some point.
+    fn spawn(&mut self, id: DeviceId, label: String, keep_trying: bool, connect: Connect) {
+        let config = Arc::clone(&self.config);
+        let parked = Arc::clone(&self.parked);
+        let record = self.record.clone();
+        let unplugged = Arc::new(AtomicBool::new(false));
+        let retry = Retry {
+            keep_trying,
+            unplugged: Arc::clone(&unplugged),
+        };
+        let thread_label = label.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("touche-{}", label))
+            .spawn(move || {
+                run_session(
+                    connect,
+                    &retry,
+                    config,
+                    record.as_deref(),
+                    &thread_label,
+                    &parked,
+                )
+            });
+
+        match spawned {
+            Ok(handle) => {
+                self.sessions.insert(
+                    id,
+                    Session {
+                        label,
+                        handle,
+                        unplugged,
+                    },
+                );
+            }
+            Err(e) => {
+                error!("failed to spawn a session thread!");
+                info!("error logs:\n{}", e);
+            }
+        }
+    }
+
+    /// Forgets a phone that went away. Its session notices on its own,
+    /// on the next failed transfer, and parks its devices.
+    pub(crate) fn disconnected(&mut self, id: DeviceId) {
+        if let Some(session) = self.sessions.get(&id) {
+            info!("device {} disconnected", session.label);
+            session.unplugged.store(true, Ordering::Relaxed);
+        }
+        self.reap();
+    }
+
+    /// Joins sessions that have already finished and destroys devices
+    /// whose phone didn't come back in time.
+    fn reap(&mut self) {
+        let finished: Vec<DeviceId> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.handle.is_finished())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in finished {
+            if let Some(session) = self.sessions.remove(&id)
+                && session.handle.join().is_err()
+            {
+                error!("session {} panicked!", session.label);
+            }
+        }
+
+        reap_parked(&self.parked);
+    }
+}
+
+/// Destroys devices whose phone didn't come back in time.
+fn reap_parked(parked: &ParkingLot) {
+    parked.lock().unwrap().retain(|label, parked| {
+        let keep = parked.since.elapsed() < PARK_TIMEOUT;
+        if !keep {
+            info!("{} didn't come back, destroying its devices", label);
+        }
+        keep
+    });
+}
+
+/// When a session tries again.
+struct Retry {
+    /// keep trying for as long as the phone is plugged in, and even after
+    /// it went away cleanly, rather than stopping after a few failures
+    keep_trying: bool,
+    unplugged: Arc<AtomicBool>,
+}
+
+fn run_session(
+    mut connect: Connect,
+    retry: &Retry,
+    config: Arc<LiveConfig>,
+    record: Option<&Path>,
+    label: &str,
+    parked: &ParkingLot,
+) {
+    let mut options = LiveOptions::new(config, label);
+    let mut devices = unpark(parked, label);
+
+    let mut backoff = FIRST_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        // a phone that keeps failing gets retried every few seconds for as
+        // long as it's plugged in, so only the first failure in a row is an error
+        let level = if retry.keep_trying && attempt > 1 {
+            Level::Info
+        } else {
+            Level::Error
+        };
+        match connect() {
+            Ok(mut source) => {
+                info!("{} connected. starting driver loop...", label);
+                match drive(source.as_mut(), record, &mut options, label, &mut devices) {
+                    Ok(_) => {
+                        info!("{} went away", label);
+                        if !retry.keep_trying {
+                            break;
+                        }
+                        // it worked, so whatever comes next is a fresh start
+                        backoff = FIRST_BACKOFF;
+                        attempt = 0;
+                    }
+                    Err(e) => {
+                        log!(level, "{} failed: {}", label, e);
+                        info!("error logs:\n{:?}", e);
+                        info!("if at first you don't succeed, die, die again!");
+                    }
+                }
+            }
+            Err(e) => {
+                log!(level, "failed to connect to {}! {}", label, e);
+                info!("error logs:\n{:?}", e);
+            }
+        }
+
+        attempt += 1;
+        if !retry.keep_trying && attempt > MAX_ATTEMPTS {
+            break;
+        }
+        if retry.keep_trying {
+            info!("retrying {} in {:?} (attempt {})", label, backoff, attempt);
+        } else {
+            info!(
+                "retrying {} in {:?} (attempt {}/{})",
+                label, backoff, attempt, MAX_ATTEMPTS
+            );
+        }
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        if retry.unplugged.load(Ordering::Relaxed) {
+            info!("{} was unplugged, not retrying", label);
+            break;
+        }
+    }
+
+    park(parked, label, devices);
+    info!("session {} finished", label);
+}
+
+/// Runs one connection, recorded into a file of its own under `record`
+/// if there is one. Every connection starts with a fresh handshake, so
+/// every recording can be replayed on its own.
+fn drive(
+    source: &mut dyn ToucheSource,
+    record: Option<&Path>,
+    options: &mut LiveOptions,
+    label: &str,
+    devices: &mut Option<Devices>,
+) -> Result<(), Error> {
+    let recorder = record.and_then(|dir| {
+        Recorder::create(dir, label)
+            .inspect_err(|e| {
+                error!("{}, driving without recording!", e);
+                info!("error logs:\n{:?}", e);
+            })
+            .ok()
+    });
+    match recorder {
+        Some(recorder) => driver_loop(
+            &mut RecordedSource { source, recorder },
+            options,
+            label,
+            devices,
+        ),
+        None => driver_loop(source, options, label, devices),
+    }
+}
+
+/// Devices a phone with this label left behind, if they're still around.
+fn unpark(parked: &ParkingLot, label: &str) -> Option<Devices> {
+    parked
+        .lock()
+        .unwrap()
+        .remove(label)
+        .map(|parked| parked.devices)
+}
+
+fn park(parked: &ParkingLot, label: &str, devices: Option<Devices>) {
+    if let Some(devices) = devices {
+        info!("parking devices of {}", label);
+        parked.lock().unwrap().insert(
+            label.to_owned(),
+            Parked {
+                devices,
+                since: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Serial number if the phone has one, bus address otherwise.
+pub(crate) fn session_label(device_info: &DeviceInfo) -> String {
+    match device_info.serial_number() {
+        Some(serial) if !serial.is_empty() => serial.to_owned(),
+        _ => format!(
+            "{}-{}",
+            device_info.bus_number(),
+            device_info.device_address()
+        ),
+    }
+}
@@ -0,0 +1,79 @@
+use serde::Deserialize;
+
+use crate::{
+    data::{ToucheData, capabilities::Capabilities},
+    driver::DriverOptions,
+    error::Error,
+};
+
+#[cfg(target_os = "linux")]
+use crate::{graphics_tablet::GraphicsTabletDevice, touchpad::TouchpadDevice};
+
+/// Somewhere decoded input goes, like a virtual device.
+/// It lives as long as the [`Devices`](crate::driver::Devices) it belongs to.
+pub(crate) trait InputSink: Send {
+    /// Builds the sink for a phone. `Ok(None)` when the phone has nothing
+    /// for it, like a tablet for a phone without a stylus.
+    fn create(spec: &SinkSpec) -> Result<Option<Self>, Error>
+    where
+        Self: Sized;
+
+    /// Takes one frame of events, already rotated to the host's point of view.
+    fn consume(&mut self, frame: &[ToucheData]) -> Result<(), Error>;
+
+    /// Called after every read from the phone, data or not. Sinks that
+    /// hold state which goes stale with time deal with it here.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Applies options that don't need a new sink.
+    fn update(&mut self, _spec: &SinkSpec) {}
+
+    /// The phone went away: lift everything that's still down.
+    /// The sink may be picked up again if the phone comes back.
+    fn shutdown(&mut self) -> Result<(), Error>;
+}
+
+/// What a sink gets built for.
+pub(crate) struct SinkSpec<'a> {
+    /// session label, to tell the devices of several phones apart
+    pub(crate) label: &'a str,
+    pub(crate) width: i32,
+    pub(crate) height: i32,
+    pub(crate) capabilities: &'a Capabilities,
+    pub(crate) options: &'a DriverOptions,
+}
+
+/// Every kind of sink there is, as named in the config.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SinkKind {
+    Tablet,
+    Touchpad,
+}
+
+impl SinkKind {
+    pub(crate) fn create(self, spec: &SinkSpec) -> Result<Option<Box<dyn InputSink>>, Error> {
+        match self {
+            #[cfg(target_os = "linux")]
+            SinkKind::Tablet => boxed(GraphicsTabletDevice::create(spec)),
+            #[cfg(target_os = "linux")]
+            SinkKind::Touchpad => boxed(TouchpadDevice::create(spec)),
+            // virtual devices are uinput only for now
+            #[cfg(not(target_os = "linux"))]
+            _ => {
+                log::warn!("the {:?} sink isn't available on this platform", self);
+                let _ = spec;
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn boxed<S: InputSink + 'static>(
+    sink: Result<Option<S>, Error>,
+) -> Result<Option<Box<dyn InputSink>>, Error> {
+    Ok(sink?.map(|sink| Box::new(sink) as Box<dyn InputSink>))
+}
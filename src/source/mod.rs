@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// Where the bytes of a phone come from, like the USB accessory interface.
+///
+/// Sources only move bytes around, making sense of them is up to the driver.
+pub(crate) trait ToucheSource {
+    /// Sends the handshake request and waits for the phone's answer to it.
+    fn handshake(&mut self, request: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// The next buffer from the phone, or `Ok(None)` if nothing came within `timeout`.
+    /// A phone that went away is an error that [`Error::is_disconnect`] recognizes.
+    fn read_frame(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Tells the phone what to do next, like to start streaming.
+    fn send_opcode(&mut self, opcode: &[u8]) -> Result<(), Error>;
+}
@@ -1,21 +1,57 @@
+#[cfg(target_os = "linux")]
 use std::io;
 
-use crate::data::ToucheData;
+#[cfg(target_os = "linux")]
+use crate::{
+    data::ToucheData,
+    error::Error,
+    sink::{InputSink, SinkSpec},
+};
 
 #[cfg(target_os = "linux")]
 use evdev::{
     uinput::VirtualDevice, AbsInfo, AbsoluteAxisCode, AbsoluteAxisEvent, AttributeSet, BusType, InputEvent, InputId, KeyCode, KeyEvent, PropType, UinputAbsSetup
 };
-use log::trace;
+#[cfg(target_os = "linux")]
+use log::{info, trace};
+
+/// Per-profile settings of the virtual touchpad.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TouchpadSettings {
+    /// device name, the session label gets appended to it
+    pub(crate) name: String,
+    pub(crate) vendor_id: u16,
+    pub(crate) product_id: u16,
+}
+
+impl Default for TouchpadSettings {
+    fn default() -> Self {
+        TouchpadSettings {
+            name: "touchepad".to_owned(),
+            vendor_id: 0x5120,
+            product_id: 0x0002,
+        }
+    }
+}
 
 #[cfg(target_os = "linux")]
 pub(crate) struct TouchpadDevice {
     device: VirtualDevice,
+    /// touch id holding each slot, so ids the phone hands out don't have to
+    /// fit the slot count and fingers can be lifted if the phone goes away
+    active_slots: Vec<Option<i32>>,
 }
 
 #[cfg(target_os = "linux")]
 impl TouchpadDevice {
-    pub(crate) fn new(width: i32, height: i32) -> io::Result<TouchpadDevice> {
+    pub(crate) fn new(
+        name: &str,
+        width: i32,
+        height: i32,
+        max_touches: u8,
+        settings: &TouchpadSettings,
+    ) -> io::Result<TouchpadDevice> {
+        let max_touches = i32::from(max_touches.max(1));
         let mut touchepad_keys: AttributeSet<KeyCode> = AttributeSet::new();
         touchepad_keys.insert(KeyCode::BTN_TOUCH);
         touchepad_keys.insert(KeyCode::BTN_TOOL_FINGER);
@@ -28,12 +64,12 @@ impl TouchpadDevice {
         touchepad_props.insert(PropType::POINTER);
 
         let device = evdev::uinput::VirtualDevice::builder()?
-            .name("touchepad")
+            .name(name)
             .with_properties(&touchepad_props)?
             .with_keys(&touchepad_keys)?
             .with_absolute_axis(&UinputAbsSetup::new(
                 AbsoluteAxisCode::ABS_MT_SLOT,
-                AbsInfo::new(0, 0, 10, 0, 0, 100),
+                AbsInfo::new(0, 0, max_touches - 1, 0, 0, 100),
             ))?
             .with_absolute_axis(&UinputAbsSetup::new(
                 AbsoluteAxisCode::ABS_MT_TRACKING_ID,
@@ -55,9 +91,17 @@ impl TouchpadDevice {
                 AbsoluteAxisCode::ABS_Y,
                 AbsInfo::new(0, 0, height, 0, 0, 100),
             ))?
-            .input_id(InputId::new(BusType::BUS_USB, 0x5120, 0x0002, 0x1))
+            .input_id(InputId::new(
+                BusType::BUS_USB,
+                settings.vendor_id,
+                settings.product_id,
+                0x1,
+            ))
             .build()?;
-        Ok(TouchpadDevice { device })
+        Ok(TouchpadDevice {
+            device,
+            active_slots: vec![None; max_touches as usize],
+        })
     }
 
     pub(crate) fn emit(&mut self, touche_data: &[ToucheData]) -> Result<(), io::Error> {
@@ -68,7 +112,9 @@ impl TouchpadDevice {
                 ToucheData::ScreenSize { .. } => {
                     // screen size event - do nothing
                 }
-                ToucheData::StylusFrame { .. } => {}
+                ToucheData::StylusFrame { .. }
+                | ToucheData::Capabilities(_)
+                | ToucheData::Rotation(_) => {}
                 ToucheData::TouchFrame {
                     x,
                     y,
@@ -76,7 +122,10 @@ impl TouchpadDevice {
                     pressed,
                 } => {
                     trace!("parsing touch frame");
-                    let mt_slot = touch_id % 10;
+                    let Some(mt_slot) = self.slot(*touch_id, *pressed) else {
+                        trace!("no free slot for touch {}, dropping it", touch_id);
+                        continue;
+                    };
                     if *pressed {
                         finger_count += 1;
                     }
@@ -87,8 +136,14 @@ impl TouchpadDevice {
                             AbsoluteAxisCode::ABS_MT_TRACKING_ID,
                             if *pressed { *touch_id } else { -1 },
                         ),
-                        *AbsoluteAxisEvent::new(AbsoluteAxisCode::ABS_MT_POSITION_X, *x),
-                        *AbsoluteAxisEvent::new(AbsoluteAxisCode::ABS_MT_POSITION_Y, *y),
+                        *AbsoluteAxisEvent::new(
+                            AbsoluteAxisCode::ABS_MT_POSITION_X,
+                            x.round() as i32,
+                        ),
+                        *AbsoluteAxisEvent::new(
+                            AbsoluteAxisCode::ABS_MT_POSITION_Y,
+                            y.round() as i32,
+                        ),
                     ]);
                 }
             }
@@ -106,6 +161,87 @@ impl TouchpadDevice {
             ]);
             return self.device.emit(&trackpad_events);
         }
-        return Result::Ok(());
+        Result::Ok(())
+    }
+
+    /// Slot of `touch_id`, a free one for a new finger. A lifted finger
+    /// gives its slot back, but still gets it for this last event.
+    fn slot(&mut self, touch_id: i32, pressed: bool) -> Option<i32> {
+        let slots = &mut self.active_slots;
+        let slot = match slots.iter().position(|id| *id == Some(touch_id)) {
+            Some(slot) => slot,
+            None if pressed => slots.iter().position(Option::is_none)?,
+            // lifting a finger that never got a slot
+            None => return None,
+        };
+        slots[slot] = pressed.then_some(touch_id);
+        Some(slot as i32)
+    }
+
+    /// Lifts every finger still down.
+    pub(crate) fn release_all(&mut self) -> Result<(), io::Error> {
+        let mut trackpad_events: Vec<InputEvent> = vec![];
+        for (mt_slot, active) in self.active_slots.iter_mut().enumerate() {
+            if active.take().is_some() {
+                trackpad_events.push(*AbsoluteAxisEvent::new(
+                    AbsoluteAxisCode::ABS_MT_SLOT,
+                    mt_slot as i32,
+                ));
+                trackpad_events.push(*AbsoluteAxisEvent::new(
+                    AbsoluteAxisCode::ABS_MT_TRACKING_ID,
+                    -1,
+                ));
+            }
+        }
+        if trackpad_events.is_empty() {
+            return Ok(());
+        }
+        trace!("releasing {} touch slots", trackpad_events.len() / 2);
+        trackpad_events.append(&mut vec![
+            *KeyEvent::new(KeyCode::BTN_TOUCH, 0),
+            *KeyEvent::new(KeyCode::BTN_TOOL_FINGER, 0),
+            *KeyEvent::new(KeyCode::BTN_TOOL_DOUBLETAP, 0),
+            *KeyEvent::new(KeyCode::BTN_TOOL_TRIPLETAP, 0),
+            *KeyEvent::new(KeyCode::BTN_TOOL_QUADTAP, 0),
+            *KeyEvent::new(KeyCode::BTN_TOOL_QUINTTAP, 0),
+        ]);
+        self.device.emit(&trackpad_events)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl InputSink for TouchpadDevice {
+    fn create(spec: &SinkSpec) -> Result<Option<Self>, Error> {
+        if spec.capabilities.max_touches == 0 {
+            info!("phone has no touch input, skipping the touchpad");
+            return Ok(None);
+        }
+        let settings = &spec.options.touchpad;
+        TouchpadDevice::new(
+            &format!("{}-{}", settings.name, spec.label),
+            spec.width,
+            spec.height,
+            spec.capabilities.max_touches,
+            settings,
+        )
+        .map(Some)
+        .map_err(|source| Error::Uinput {
+            device: "touchpad",
+            source,
+        })
+    }
+
+    fn consume(&mut self, frame: &[ToucheData]) -> Result<(), Error> {
+        self.emit(frame).map_err(|source| Error::Emit {
+            device: "touchpad",
+            source,
+        })
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        self.release_all().map_err(|source| Error::Emit {
+            device: "touchpad",
+            source,
+        })
     }
 }